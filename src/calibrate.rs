@@ -0,0 +1,267 @@
+use std::time::Duration;
+
+use crate::fan::CalibrationPoint;
+
+/// How close to the fastest speed seen a command has to get to count as saturating the fan,
+/// as a fraction of that speed.
+const SATURATION_MARGIN: f64 = 0.03;
+
+/// The speed commands to try, from `from` towards `to` in steps of `step`. Sweeping from the
+/// slow end finds the command a stopped fan starts at rather than the one it keeps turning at.
+#[derive(Clone, Debug)]
+pub struct Sweep {
+    pub from: u8,
+    pub to: u8,
+    pub step: u8,
+}
+
+impl Sweep {
+    pub fn commands(&self) -> Vec<u8> {
+        let step = self.step.max(1) as usize;
+        let mut commands: Vec<u8> = if self.from <= self.to {
+            (self.from..=self.to).step_by(step).collect()
+        } else {
+            (self.to..=self.from).rev().step_by(step).collect()
+        };
+        if commands.last() != Some(&self.to) {
+            commands.push(self.to);
+        }
+        commands
+    }
+}
+
+/// When a fan counts as having reached the speed for a command: the last `readings` RPM
+/// readings, taken every `interval`, lie within `tolerance` of each other, or `timeout` has
+/// passed.
+#[derive(Clone, Debug)]
+pub struct Settling {
+    pub interval: Duration,
+    pub readings: usize,
+    pub tolerance: f64,
+    pub timeout: Duration,
+}
+
+impl Default for Settling {
+    fn default() -> Settling {
+        Settling {
+            interval: Duration::from_millis(500),
+            readings: 4,
+            tolerance: 0.02,
+            timeout: Duration::from_secs(20),
+        }
+    }
+}
+
+impl Settling {
+    /// Whether the latest readings are steady, allowing a few RPM of jitter at low speeds.
+    pub fn is_settled(&self, rpms: &[u32]) -> bool {
+        if rpms.len() < self.readings {
+            return false;
+        }
+        let latest = &rpms[rpms.len() - self.readings..];
+        let (min, max) = (*latest.iter().min().unwrap(), *latest.iter().max().unwrap());
+        (max - min) as f64 <= (max as f64 * self.tolerance).max(30.0)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Measurement {
+    pub command: u8,
+    pub rpm: u32,
+    pub settled: bool,
+}
+
+#[derive(Clone, Debug)]
+pub struct Calibration {
+    /// The slowest command that starts the fan.
+    pub spin_up: Measurement,
+    /// The slowest command past which the fan does not get noticeably faster.
+    pub saturation: Measurement,
+    /// Duty 0% is the last command that leaves the fan stopped, if any did, and 100% is the
+    /// saturation point; in between, duty is the share of the saturation speed.
+    pub points: Vec<CalibrationPoint>,
+}
+
+/// Works out a calibration table from the speeds measured over a sweep, in whichever
+/// direction commands make the fan faster.
+pub fn analyse(measurements: &[Measurement]) -> Result<Calibration, String> {
+    let mut by_speed = measurements.to_vec();
+    by_speed.sort_by_key(|m| m.command);
+    if let (Some(first), Some(last)) = (by_speed.first(), by_speed.last()) {
+        if last.rpm < first.rpm {
+            by_speed.reverse();
+        }
+    }
+    let spin_up = by_speed
+        .iter()
+        .position(|m| m.rpm > 0)
+        .ok_or("the fan never turned; check its speed and RPM registers and the range swept")?;
+    let fastest = by_speed.iter().map(|m| m.rpm).max().unwrap() as f64;
+    let saturation = spin_up
+        + by_speed[spin_up..]
+            .iter()
+            .position(|m| m.rpm as f64 >= fastest * (1.0 - SATURATION_MARGIN))
+            .unwrap();
+    let saturation_rpm = by_speed[saturation].rpm as f64;
+
+    let mut points: Vec<CalibrationPoint> = Vec::new();
+    if spin_up > 0 {
+        let stopped = by_speed[spin_up - 1];
+        points.push(CalibrationPoint {
+            duty: 0.0,
+            command: stopped.command,
+            rpm: Some(0),
+        });
+    }
+    for m in &by_speed[spin_up..=saturation] {
+        let duty = (m.rpm as f64 / saturation_rpm * 1000.0).round() / 10.0;
+        // Readings that dip below a slower command's are noise, and would make the table
+        // non-monotonic.
+        if points.last().is_some_and(|p| duty <= p.duty) {
+            continue;
+        }
+        points.push(CalibrationPoint {
+            duty,
+            command: m.command,
+            rpm: Some(m.rpm),
+        });
+    }
+    if points.len() < 2 {
+        return Err(format!(
+            "the fan reached full speed at {:#04x} without a usable range below it; sweep from \
+             a slower command",
+            by_speed[saturation].command
+        ));
+    }
+    Ok(Calibration {
+        spin_up: by_speed[spin_up],
+        saturation: by_speed[saturation],
+        points,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn measure(rpms: &[(u8, u32)]) -> Vec<Measurement> {
+        rpms.iter()
+            .map(|&(command, rpm)| Measurement {
+                command,
+                rpm,
+                settled: true,
+            })
+            .collect()
+    }
+
+    fn points(calibration: &Calibration) -> Vec<(f64, u8)> {
+        calibration
+            .points
+            .iter()
+            .map(|p| (p.duty, p.command))
+            .collect()
+    }
+
+    #[test]
+    fn finds_spin_up_and_saturation() {
+        let calibration = analyse(&measure(&[
+            (0x00, 0),
+            (0x08, 0),
+            (0x10, 1200),
+            (0x18, 2400),
+            (0x20, 3600),
+            (0x28, 3950),
+            (0x30, 4000),
+            (0x38, 3990),
+        ]))
+        .unwrap();
+        assert_eq!(calibration.spin_up.command, 0x10);
+        // 3950 is within 3% of the fastest speed seen.
+        assert_eq!(calibration.saturation.command, 0x28);
+        assert_eq!(
+            points(&calibration),
+            [
+                (0.0, 0x08),
+                (30.4, 0x10),
+                (60.8, 0x18),
+                (91.1, 0x20),
+                (100.0, 0x28)
+            ]
+        );
+        assert_eq!(calibration.points[0].rpm, Some(0));
+    }
+
+    #[test]
+    fn handles_a_fan_already_spinning_at_the_slowest_command() {
+        let calibration = analyse(&measure(&[
+            (0x38, 1500),
+            (0x40, 2500),
+            (0x48, 3000),
+            (0x50, 3000),
+        ]))
+        .unwrap();
+        assert_eq!(calibration.spin_up.command, 0x38);
+        assert_eq!(calibration.saturation.command, 0x48);
+        assert_eq!(
+            points(&calibration),
+            [(50.0, 0x38), (83.3, 0x40), (100.0, 0x48)]
+        );
+    }
+
+    #[test]
+    fn handles_fans_where_lower_commands_are_faster() {
+        let calibration = analyse(&measure(&[
+            (0x00, 4000),
+            (0x40, 4000),
+            (0x80, 2000),
+            (0xff, 0),
+        ]))
+        .unwrap();
+        assert_eq!(calibration.spin_up.command, 0x80);
+        assert_eq!(calibration.saturation.command, 0x40);
+        assert_eq!(
+            points(&calibration),
+            [(0.0, 0xff), (50.0, 0x80), (100.0, 0x40)]
+        );
+    }
+
+    #[test]
+    fn skips_readings_that_dip() {
+        let calibration = analyse(&measure(&[
+            (0x10, 0),
+            (0x11, 2000),
+            (0x12, 1900),
+            (0x13, 4000),
+        ]))
+        .unwrap();
+        assert_eq!(
+            points(&calibration),
+            [(0.0, 0x10), (50.0, 0x11), (100.0, 0x13)]
+        );
+    }
+
+    #[test]
+    fn rejects_sweeps_without_a_usable_range() {
+        assert!(analyse(&measure(&[(0x10, 0), (0x20, 0)])).is_err());
+        assert!(analyse(&measure(&[(0x10, 4000), (0x20, 4000)])).is_err());
+    }
+
+    #[test]
+    fn sweeps_in_either_direction_and_ends_on_the_last_command() {
+        let sweep = |from, to, step| Sweep { from, to, step }.commands();
+        assert_eq!(sweep(0x10, 0x20, 8), [0x10, 0x18, 0x20]);
+        assert_eq!(sweep(0x10, 0x1c, 8), [0x10, 0x18, 0x1c]);
+        assert_eq!(sweep(0x20, 0x10, 8), [0x20, 0x18, 0x10]);
+        assert_eq!(sweep(0x10, 0x12, 0), [0x10, 0x11, 0x12]);
+    }
+
+    #[test]
+    fn settles_once_readings_steady() {
+        let settling = Settling::default();
+        assert!(!settling.is_settled(&[3000, 3000, 3000]));
+        assert!(!settling.is_settled(&[2000, 2600, 3000, 3000]));
+        assert!(settling.is_settled(&[2000, 3000, 3020, 2990, 3010]));
+        // A few RPM of jitter count as steady at low speeds.
+        assert!(settling.is_settled(&[400, 420, 410, 395]));
+    }
+}
@@ -0,0 +1,146 @@
+use std::convert::TryFrom;
+use std::fmt;
+use std::str::FromStr;
+use std::time::Duration;
+
+use crate::dmi::{self, DmiInfo};
+use crate::ec::{self, EcBackend, WriteVerification};
+use crate::profile::{self, ProfileError, SelectedProfile};
+use crate::regmap::{RegisterMap, RegisterMapError};
+use crate::sensor;
+
+/// Options that never take a value; every other `--option` consumes the argument after it.
+const FLAGS: &[&str] = &["--no-read-back", "--force"];
+
+/// Command line arguments as `[command] [positional...]` mixed freely with `--option value`.
+pub struct Args {
+    args: Vec<String>,
+}
+
+impl Args {
+    pub fn from_env() -> Args {
+        Args {
+            args: std::env::args().skip(1).collect(),
+        }
+    }
+
+    pub fn command(&self) -> Option<&str> {
+        self.positional().first().copied()
+    }
+
+    /// Positional arguments following the command.
+    pub fn operands(&self) -> Vec<&str> {
+        self.positional().into_iter().skip(1).collect()
+    }
+
+    pub fn value(&self, name: &str) -> Option<&str> {
+        self.args
+            .iter()
+            .position(|arg| arg == name)
+            .and_then(|i| self.args.get(i + 1))
+            .map(String::as_str)
+    }
+
+    pub fn parsed<T>(&self, name: &str) -> Result<Option<T>, String>
+    where
+        T: FromStr,
+        T::Err: fmt::Display,
+    {
+        self.value(name)
+            .map(|value| {
+                value
+                    .parse()
+                    .map_err(|e| format!("invalid {} '{}': {}", name, value, e))
+            })
+            .transpose()
+    }
+
+    /// A register value given in decimal or as `0x..` hex.
+    pub fn byte(&self, name: &str) -> Result<Option<u8>, String> {
+        self.value(name)
+            .map(|value| {
+                ec::parse_register_number(value)
+                    .ok()
+                    .and_then(|n| u8::try_from(n).ok())
+                    .ok_or_else(|| format!("invalid {} '{}': expected a byte", name, value))
+            })
+            .transpose()
+    }
+
+    /// Opens the EC backend chosen with `--ec`.
+    pub fn open_ec(&self) -> std::io::Result<Box<dyn EcBackend>> {
+        ec::open_backend(self.value("--ec").unwrap_or(ec::DEFAULT_EC_BACKEND))
+    }
+
+    /// Reads this machine's DMI fields, or those saved in the directory given with `--dmi`.
+    pub fn dmi(&self) -> std::io::Result<DmiInfo> {
+        DmiInfo::read(self.value("--dmi").unwrap_or(dmi::DMI_DIR))
+    }
+
+    /// Picks the machine profile given with `--profile`, or the one that matches this machine
+    /// among those in `--profile-dir` and the built-in ones.
+    pub fn profile(&self) -> Result<SelectedProfile, ProfileError> {
+        profile::select(self.value("--profile"), self.profile_dir(), self.dmi()?)
+    }
+
+    pub fn profile_dir(&self) -> &str {
+        self.value("--profile-dir").unwrap_or(profile::PROFILE_DIR)
+    }
+
+    /// Where thermal zones are looked for: `--thermal-dir`, or the kernel's.
+    pub fn thermal_dir(&self) -> &str {
+        self.value("--thermal-dir").unwrap_or(sensor::THERMAL_DIR)
+    }
+
+    /// Where hwmon devices are looked for: `--hwmon-dir`, or the kernel's.
+    pub fn hwmon_dir(&self) -> &str {
+        self.value("--hwmon-dir").unwrap_or(sensor::HWMON_DIR)
+    }
+
+    /// The `nvidia-smi` to run: `--nvidia-smi`, or the one on the `PATH`.
+    pub fn nvidia_smi(&self) -> &str {
+        self.value("--nvidia-smi").unwrap_or(sensor::NVIDIA_SMI)
+    }
+
+    /// Adds the names from `--register-map` to `builtin`.
+    pub fn register_map(&self, builtin: RegisterMap) -> Result<RegisterMap, RegisterMapError> {
+        let mut map = builtin;
+        if let Some(path) = self.value("--register-map") {
+            map.merge(RegisterMap::load(path)?);
+        }
+        Ok(map)
+    }
+
+    pub fn flag(&self, name: &str) -> bool {
+        self.args.iter().any(|arg| arg == name)
+    }
+
+    fn positional(&self) -> Vec<&str> {
+        let mut positional = Vec::new();
+        let mut args = self.args.iter();
+        while let Some(arg) = args.next() {
+            if arg.starts_with("--") {
+                if !FLAGS.contains(&arg.as_str()) {
+                    args.next();
+                }
+            } else {
+                positional.push(arg.as_str());
+            }
+        }
+        positional
+    }
+}
+
+pub fn write_verification_from_args(args: &Args) -> Result<WriteVerification, String> {
+    let mut verification = WriteVerification::default();
+    if args.flag("--no-read-back") {
+        verification.read_back = false;
+    }
+    if let Some(retries) = args.parsed("--write-retries")? {
+        verification.retries = retries;
+    }
+    if let Some(backoff) = args.parsed("--write-backoff-ms")? {
+        verification.backoff = Duration::from_millis(backoff);
+    }
+    Ok(verification)
+}
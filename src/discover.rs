@@ -0,0 +1,437 @@
+use std::collections::BTreeMap;
+use std::io;
+
+use crate::dmi::DmiInfo;
+use crate::ec::field::RegisterPair;
+use crate::ec::EC_REGISTER_COUNT;
+use crate::fan::{self, CalibrationPoint};
+use crate::profile::{FanProfile, MatchRule, Profile, SensorProfile, ZoneProfile};
+use crate::sensor::{self, TemperatureSource, ThermalZone, ThermalZoneInfo};
+
+/// What the user is asked to do while the EC is recorded, in order. Load should move
+/// temperatures and fan speeds together, and switching the fan mode should move the control
+/// registers without a matching change in temperature.
+pub const PHASES: &[(&str, &str)] = &[
+    ("idle", "Leave the machine idle"),
+    (
+        "load",
+        "Start a heavy load, such as a stress test or a game",
+    ),
+    (
+        "fan mode",
+        "Stop the load, then switch the fan or performance mode back and forth with the \
+         vendor's hotkey, app or BIOS setting",
+    ),
+];
+
+const FAN_MODE_PHASE: usize = 2;
+
+/// Registers with more distinct values than this look like readings rather than settings.
+const MAX_CONTROL_VALUES: usize = 4;
+
+/// How many candidates of each kind to report.
+pub const CANDIDATES_SHOWN: usize = 5;
+
+/// A sensor for the hottest thermal zone right now, as the load phase should make it the one
+/// that moves most. It is picked by type when no other zone shares the type.
+pub async fn hottest_thermal_zone(dir: &str) -> io::Result<Option<SensorProfile>> {
+    let zones = sensor::thermal_zones(dir)?;
+    let mut hottest: Option<(f64, &ThermalZoneInfo)> = None;
+    for zone in &zones {
+        let source: &dyn TemperatureSource = &ThermalZone::from_info(zone);
+        if let Ok(temperature) = source.read_celsius().await {
+            if hottest.is_none_or(|(t, _)| temperature > t) {
+                hottest = Some((temperature, zone));
+            }
+        }
+    }
+    Ok(hottest.map(|(_, zone)| {
+        if zones
+            .iter()
+            .filter(|z| z.zone_type == zone.zone_type)
+            .count()
+            == 1
+        {
+            SensorProfile::ThermalZoneType(vec![zone.zone_type.clone()])
+        } else {
+            SensorProfile::ThermalZone(zone.temp_path())
+        }
+    }))
+}
+
+#[derive(Clone, Debug)]
+pub struct Sample {
+    pub phase: usize,
+    pub temperature: f64,
+    pub registers: Vec<u8>,
+}
+
+/// Samples of the whole register space and a reference temperature over the phases.
+#[derive(Clone, Debug, Default)]
+pub struct Recording {
+    pub samples: Vec<Sample>,
+}
+
+#[derive(Clone, Debug)]
+pub struct Candidate {
+    pub register_offset: u64,
+    pub score: f64,
+    pub reason: String,
+}
+
+#[derive(Clone, Debug)]
+pub struct RpmCandidate {
+    pub registers: RegisterPair,
+    pub score: f64,
+    pub reason: String,
+}
+
+#[derive(Clone, Debug)]
+pub struct ControlCandidate {
+    pub register_offset: u64,
+    pub score: f64,
+    /// Every value seen, in the order first seen; the first is the firmware's own setting.
+    pub values: Vec<u8>,
+    pub reason: String,
+}
+
+/// Pearson correlation, or `None` when either series is constant.
+pub fn correlation(xs: &[f64], ys: &[f64]) -> Option<f64> {
+    let n = xs.len().min(ys.len()) as f64;
+    if n < 2.0 {
+        return None;
+    }
+    let (mean_x, mean_y) = (xs.iter().sum::<f64>() / n, ys.iter().sum::<f64>() / n);
+    let (mut covariance, mut variance_x, mut variance_y) = (0.0, 0.0, 0.0);
+    for (x, y) in xs.iter().zip(ys) {
+        covariance += (x - mean_x) * (y - mean_y);
+        variance_x += (x - mean_x) * (x - mean_x);
+        variance_y += (y - mean_y) * (y - mean_y);
+    }
+    if variance_x == 0.0 || variance_y == 0.0 {
+        return None;
+    }
+    Some(covariance / (variance_x * variance_y).sqrt())
+}
+
+impl Recording {
+    pub fn push(&mut self, sample: Sample) {
+        self.samples.push(sample);
+    }
+
+    fn temperatures(&self) -> Vec<f64> {
+        self.samples.iter().map(|s| s.temperature).collect()
+    }
+
+    fn register_values(&self, register_offset: u64) -> Vec<f64> {
+        self.samples
+            .iter()
+            .map(|s| s.registers[register_offset as usize] as f64)
+            .collect()
+    }
+
+    /// Registers that follow the reference temperature and read like degrees Celsius.
+    pub fn temperature_candidates(&self) -> Vec<Candidate> {
+        let temperatures = self.temperatures();
+        let mut candidates: Vec<Candidate> = (0..EC_REGISTER_COUNT)
+            .filter_map(|offset| {
+                let values = self.register_values(offset);
+                let corr = correlation(&values, &temperatures)?;
+                let plausible = values
+                    .iter()
+                    .filter(|v| (15.0..=110.0).contains(*v))
+                    .count() as f64
+                    / values.len() as f64;
+                let mean_error = values
+                    .iter()
+                    .zip(&temperatures)
+                    .map(|(v, t)| (v - t).abs())
+                    .sum::<f64>()
+                    / values.len() as f64;
+                let score = corr.max(0.0) * plausible / (1.0 + mean_error / 10.0);
+                Some(Candidate {
+                    register_offset: offset,
+                    score,
+                    reason: format!(
+                        "correlation {:.2}, {:.0}°C from the reference on average",
+                        corr, mean_error
+                    ),
+                })
+            })
+            .filter(|c| c.score > 0.0)
+            .collect();
+        rank(&mut candidates, |c| c.score);
+        candidates
+    }
+
+    /// Little-endian register pairs that read as a plausible fan speed the way `FRSP` works
+    /// it out, and that speed up with the reference temperature.
+    pub fn rpm_candidates(&self) -> Vec<RpmCandidate> {
+        let temperatures = self.temperatures();
+        let mut candidates: Vec<RpmCandidate> = (0..EC_REGISTER_COUNT - 1)
+            .filter_map(|offset| {
+                let registers = RegisterPair::little_endian(offset);
+                let rpms: Vec<f64> = self
+                    .samples
+                    .iter()
+                    .map(|s| {
+                        let raw = u16::from_le_bytes([
+                            s.registers[offset as usize],
+                            s.registers[offset as usize + 1],
+                        ]);
+                        fan::rpm_from_raw(raw) as f64
+                    })
+                    .collect();
+                let corr = correlation(&rpms, &temperatures)?;
+                let plausible = rpms
+                    .iter()
+                    .filter(|&&rpm| rpm == 0.0 || (300.0..=10000.0).contains(&rpm))
+                    .count() as f64
+                    / rpms.len() as f64;
+                let (min, max) = rpms
+                    .iter()
+                    .fold((f64::MAX, f64::MIN), |(lo, hi), &r| (lo.min(r), hi.max(r)));
+                Some(RpmCandidate {
+                    registers,
+                    score: corr.max(0.0) * plausible,
+                    reason: format!("correlation {:.2}, {:.0}-{:.0} RPM", corr, min, max),
+                })
+            })
+            .filter(|c| c.score > 0.0)
+            .collect();
+        rank(&mut candidates, |c| c.score);
+        // A good pair makes its neighbours look good too, as they share a register.
+        let mut kept: Vec<RpmCandidate> = Vec::new();
+        for candidate in candidates {
+            let low = candidate.registers.low;
+            if !kept.iter().any(|k| k.registers.low.abs_diff(low) < 2) {
+                kept.push(candidate);
+            }
+        }
+        kept
+    }
+
+    /// Registers with a few distinct values that change when the fan mode is switched, rather
+    /// than with load or temperature.
+    pub fn control_candidates(&self) -> Vec<ControlCandidate> {
+        let temperatures = self.temperatures();
+        let mut candidates: Vec<ControlCandidate> = (0..EC_REGISTER_COUNT)
+            .filter_map(|offset| {
+                let mut values: Vec<u8> = Vec::new();
+                let mut changes: BTreeMap<usize, usize> = BTreeMap::new();
+                let mut previous: Option<u8> = None;
+                for sample in &self.samples {
+                    let value = sample.registers[offset as usize];
+                    if !values.contains(&value) {
+                        values.push(value);
+                    }
+                    if previous.is_some_and(|p| p != value) {
+                        *changes.entry(sample.phase).or_default() += 1;
+                    }
+                    previous = Some(value);
+                }
+                let in_fan_mode = changes.get(&FAN_MODE_PHASE).copied().unwrap_or(0);
+                if in_fan_mode == 0 || values.len() > MAX_CONTROL_VALUES {
+                    return None;
+                }
+                let elsewhere: usize = changes
+                    .iter()
+                    .filter(|&(&phase, _)| phase != FAN_MODE_PHASE)
+                    .map(|(_, &n)| n)
+                    .sum();
+                let corr = correlation(&self.register_values(offset), &temperatures).unwrap_or(0.0);
+                Some(ControlCandidate {
+                    register_offset: offset,
+                    score: in_fan_mode as f64 / (1 + elsewhere) as f64 * (1.0 - corr.abs()),
+                    reason: format!(
+                        "{} changes switching the fan mode, {} otherwise, values {}",
+                        in_fan_mode,
+                        elsewhere,
+                        hex_list(&values)
+                    ),
+                    values,
+                })
+            })
+            .filter(|c| c.score > 0.0)
+            .collect();
+        rank(&mut candidates, |c| c.score);
+        candidates
+    }
+}
+
+fn rank<T>(candidates: &mut [T], score: impl Fn(&T) -> f64) {
+    candidates.sort_by(|a, b| score(b).partial_cmp(&score(a)).unwrap());
+}
+
+pub fn hex_list(values: &[u8]) -> String {
+    values
+        .iter()
+        .map(|v| format!("{:#04x}", v))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// The best candidates so far and what the user confirmed with test writes.
+pub struct Findings {
+    pub temperature: Option<Candidate>,
+    pub rpm: Option<RpmCandidate>,
+    pub control: Option<ControlCandidate>,
+    /// A test write to the control register that changed the fan speed, as the value written
+    /// and the value it replaced.
+    pub confirmed_write: Option<(u8, u8)>,
+}
+
+/// A profile for one fan driven by one zone, with the best candidates filled in. Without a
+/// confirmed test write the release value is the first value seen in the control register and
+/// the acquire value the next. The speed register and calibration table are placeholders:
+/// the speed register is taken to be the control register, and the table only ever writes the
+/// release value back, until `calibrate` measures a real one. The profile has no match rule,
+/// so that it is not picked for the machine without `--force` before it has been checked.
+pub fn draft_profile(
+    findings: &Findings,
+    sensor: SensorProfile,
+    dmi: &DmiInfo,
+) -> Result<Profile, String> {
+    let control = findings
+        .control
+        .as_ref()
+        .ok_or("no register changed with the fan mode, so there is no control register to draft")?;
+    let defaults = Profile::builtin().map_err(|e| e.to_string())?;
+    let release_control = match findings.confirmed_write {
+        Some((_, original)) => original,
+        None => control.values[0],
+    };
+    let placeholder = |duty| CalibrationPoint {
+        duty,
+        command: release_control,
+        rpm: None,
+    };
+    let fan = FanProfile {
+        name: "fan".to_string(),
+        control_register: control.register_offset,
+        acquire_control: match findings.confirmed_write {
+            Some((written, _)) => written,
+            None => control.values.get(1).copied().unwrap_or(control.values[0]),
+        },
+        release_control,
+        speed_register: control.register_offset,
+        rpm_registers: findings.rpm.as_ref().map(|c| c.registers),
+        temperature_register: findings.temperature.as_ref().map(|c| c.register_offset),
+        calibration: vec![placeholder(0.0), placeholder(100.0)],
+    };
+    let zone = ZoneProfile {
+        name: "cpu".to_string(),
+        sensors: vec![sensor],
+        fallback: Vec::new(),
+        fans: vec![fan.name.clone()],
+        controller: defaults.zones[0].controller.clone(),
+    };
+    let profile = Profile {
+        name: non_empty(&dmi.product_name).unwrap_or_else(|| "draft".to_string()),
+        matches: Vec::new(),
+        polling_interval_ms: defaults.polling_interval_ms,
+        fans: vec![fan],
+        zones: vec![zone],
+        registers: BTreeMap::new(),
+    };
+    profile.validate()?;
+    Ok(profile)
+}
+
+fn non_empty(s: &str) -> Option<String> {
+    Some(s.to_string()).filter(|s| !s.is_empty())
+}
+
+/// A rule picking the profile for this machine, to be added once the draft has been checked.
+pub fn match_rule(dmi: &DmiInfo) -> Option<MatchRule> {
+    let rule = MatchRule {
+        sys_vendor: non_empty(&dmi.sys_vendor),
+        product_name: non_empty(&dmi.product_name),
+        board_name: non_empty(&dmi.board_name),
+        ..MatchRule::default()
+    };
+    Some(rule).filter(|rule| *rule != MatchRule::default())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn findings(confirmed_write: Option<(u8, u8)>) -> Findings {
+        Findings {
+            temperature: Some(Candidate {
+                register_offset: 0x58,
+                score: 1.0,
+                reason: String::new(),
+            }),
+            rpm: None,
+            control: Some(ControlCandidate {
+                register_offset: 0x93,
+                score: 1.0,
+                values: vec![0x04, 0x14],
+                reason: String::new(),
+            }),
+            confirmed_write,
+        }
+    }
+
+    fn dmi() -> DmiInfo {
+        DmiInfo {
+            sys_vendor: "Example".to_string(),
+            product_name: "Gaming 15".to_string(),
+            ..DmiInfo::default()
+        }
+    }
+
+    fn commands(profile: &Profile) -> Vec<u8> {
+        profile.fans[0]
+            .calibration
+            .iter()
+            .map(|p| p.command)
+            .collect()
+    }
+
+    #[test]
+    fn drafts_a_profile_that_only_writes_back_the_firmware_setting() {
+        let sensor = SensorProfile::ThermalZoneType(vec!["x86_pkg_temp".to_string()]);
+        let profile = draft_profile(&findings(None), sensor.clone(), &dmi()).unwrap();
+        assert_eq!(profile.name, "Gaming 15");
+        assert!(profile.matches.is_empty());
+        let fan = &profile.fans[0];
+        assert_eq!(
+            (
+                fan.control_register,
+                fan.acquire_control,
+                fan.release_control
+            ),
+            (0x93, 0x14, 0x04)
+        );
+        assert_eq!(fan.temperature_register, Some(0x58));
+        assert_eq!(commands(&profile), [0x04, 0x04]);
+        assert_eq!(fan.command_for_duty(50.0), 0x04);
+        assert_eq!(profile.zones[0].sensors.len(), 1);
+
+        let profile = draft_profile(&findings(Some((0x20, 0x0c))), sensor, &dmi()).unwrap();
+        let fan = &profile.fans[0];
+        assert_eq!((fan.acquire_control, fan.release_control), (0x20, 0x0c));
+        assert_eq!(commands(&profile), [0x0c, 0x0c]);
+    }
+
+    #[test]
+    fn drafts_nothing_without_a_control_register() {
+        let findings = Findings {
+            control: None,
+            ..findings(None)
+        };
+        assert!(draft_profile(&findings, SensorProfile::NvidiaSmi, &dmi()).is_err());
+    }
+
+    #[test]
+    fn suggests_a_match_rule_from_the_dmi_fields_present() {
+        let rule = match_rule(&dmi()).unwrap();
+        assert_eq!(rule.sys_vendor.as_deref(), Some("Example"));
+        assert_eq!(rule.product_name.as_deref(), Some("Gaming 15"));
+        assert_eq!(rule.board_name, None);
+        assert!(match_rule(&DmiInfo::default()).is_none());
+    }
+}
@@ -0,0 +1,125 @@
+use std::cmp::Ordering;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+pub const DMI_DIR: &str = "/sys/class/dmi/id";
+
+/// The firmware's description of the machine, used to pick a machine profile.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct DmiInfo {
+    pub sys_vendor: String,
+    pub product_name: String,
+    pub board_name: String,
+    pub bios_version: String,
+}
+
+impl DmiInfo {
+    /// Reads the DMI fields from `dir`; fields the kernel does not expose are left empty.
+    pub fn read<P: AsRef<Path>>(dir: P) -> io::Result<DmiInfo> {
+        let dir = dir.as_ref();
+        let field = |name: &str| match fs::read_to_string(dir.join(name)) {
+            Ok(value) => Ok(value.trim().to_string()),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(String::new()),
+            Err(e) => Err(e),
+        };
+        Ok(DmiInfo {
+            sys_vendor: field("sys_vendor")?,
+            product_name: field("product_name")?,
+            board_name: field("board_name")?,
+            bios_version: field("bios_version")?,
+        })
+    }
+}
+
+impl fmt::Display for DmiInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let or_unknown = |s: &str| if s.is_empty() { "?" } else { s }.to_string();
+        write!(
+            f,
+            "{} {} (board {}, BIOS {})",
+            or_unknown(&self.sys_vendor),
+            or_unknown(&self.product_name),
+            or_unknown(&self.board_name),
+            or_unknown(&self.bios_version)
+        )
+    }
+}
+
+/// Compares firmware version strings such as `1.07` and `1.12` or `F.23` and `F.9`, taking
+/// runs of digits as numbers so that `1.9` comes before `1.10`.
+pub fn compare_versions(a: &str, b: &str) -> Ordering {
+    let (a, b) = (version_parts(a), version_parts(b));
+    for (a, b) in a.iter().zip(b.iter()) {
+        let ordering = match (a.parse::<u64>(), b.parse::<u64>()) {
+            (Ok(a), Ok(b)) => a.cmp(&b),
+            _ => a.to_lowercase().cmp(&b.to_lowercase()),
+        };
+        if ordering != Ordering::Equal {
+            return ordering;
+        }
+    }
+    a.len().cmp(&b.len())
+}
+
+fn version_parts(version: &str) -> Vec<String> {
+    let mut parts: Vec<String> = Vec::new();
+    let mut last_was_digit = None;
+    for c in version.chars() {
+        if !c.is_ascii_alphanumeric() {
+            last_was_digit = None;
+            continue;
+        }
+        let is_digit = c.is_ascii_digit();
+        match parts.last_mut() {
+            Some(part) if last_was_digit == Some(is_digit) => part.push(c),
+            _ => parts.push(c.to_string()),
+        }
+        last_was_digit = Some(is_digit);
+    }
+    parts
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compares_runs_of_digits_as_numbers() {
+        assert_eq!(compare_versions("1.9", "1.10"), Ordering::Less);
+        assert_eq!(compare_versions("F.23", "F.9"), Ordering::Greater);
+        assert_eq!(compare_versions("1.07", "1.7"), Ordering::Equal);
+        assert_eq!(compare_versions("f.23", "F.23"), Ordering::Equal);
+    }
+
+    #[test]
+    fn compares_mixed_versions_part_by_part() {
+        assert_eq!(version_parts("R1.10b"), ["R", "1", "10", "b"]);
+        assert_eq!(version_parts("V1.05 (2021)"), ["V", "1", "05", "2021"]);
+        assert_eq!(compare_versions("1.0a", "1.0b"), Ordering::Less);
+        assert_eq!(compare_versions("R1.10", "R1.9"), Ordering::Greater);
+        assert_eq!(compare_versions("F1.2", "F1-2"), Ordering::Equal);
+        // Where the parts run out, the version with more of them is later.
+        assert_eq!(compare_versions("1.2", "1.2.1"), Ordering::Less);
+        assert_eq!(compare_versions("1.2a", "1.2"), Ordering::Greater);
+        assert_eq!(compare_versions("", "1"), Ordering::Less);
+    }
+
+    #[test]
+    fn reads_missing_fields_as_empty() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("sys_vendor"), "Example Inc.\n").unwrap();
+        fs::write(dir.path().join("bios_version"), " 1.07 \n").unwrap();
+        let dmi = DmiInfo::read(dir.path()).unwrap();
+        assert_eq!(
+            dmi,
+            DmiInfo {
+                sys_vendor: "Example Inc.".to_string(),
+                bios_version: "1.07".to_string(),
+                ..DmiInfo::default()
+            }
+        );
+        assert_eq!(dmi.to_string(), "Example Inc. ? (board ?, BIOS 1.07)");
+    }
+}
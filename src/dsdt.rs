@@ -0,0 +1,539 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::str::FromStr;
+
+use crate::regmap::RegisterMap;
+
+pub const DSDT_FILE: &str = "/sys/firmware/acpi/tables/DSDT";
+
+const ACPI_TABLE_HEADER_LEN: usize = 36;
+const EMBEDDED_CONTROL_REGION_SPACE: u8 = 0x03;
+
+/// A named field in an `EmbeddedControl` operation region, e.g. `RPM1` at 0xb2 width 8.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct EcFieldDef {
+    pub name: String,
+    pub region: String,
+    /// Offset in bits from the start of the EC register space.
+    pub bit_offset: u64,
+    pub bit_width: u64,
+}
+
+impl EcFieldDef {
+    pub fn register_offset(&self) -> u64 {
+        self.bit_offset / 8
+    }
+
+    pub fn first_bit(&self) -> u64 {
+        self.bit_offset % 8
+    }
+}
+
+/// Reads EC fields from a table that is either compiled AML (such as the raw table in
+/// `/sys/firmware/acpi/tables`) or decompiled ASL source.
+pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Vec<EcFieldDef>> {
+    let bytes = fs::read(path)?;
+    let fields = if is_aml_table(&bytes) {
+        parse_aml(&bytes[ACPI_TABLE_HEADER_LEN..])
+    } else {
+        match String::from_utf8(bytes) {
+            Ok(source) => parse_asl(&source),
+            Err(_) => Err("neither an AML table nor ASL source".to_string()),
+        }
+    };
+    fields.map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+/// Where a field starts in the EC register space, in bits, unless the firmware places it
+/// beyond the end of the address space.
+fn field_bit_offset(
+    name: &str,
+    region: &str,
+    region_offset: u64,
+    bit_offset: u64,
+) -> Result<u64, String> {
+    region_offset
+        .checked_mul(8)
+        .and_then(|offset| offset.checked_add(bit_offset))
+        .ok_or_else(|| format!("{} in region {} lies past the end of the EC", name, region))
+}
+
+fn is_aml_table(bytes: &[u8]) -> bool {
+    bytes.len() >= ACPI_TABLE_HEADER_LEN
+        && (bytes.starts_with(b"DSDT") || bytes.starts_with(b"SSDT"))
+}
+
+fn sort_fields(mut fields: Vec<EcFieldDef>) -> Vec<EcFieldDef> {
+    fields.sort_by(|a, b| (a.bit_offset, &a.name).cmp(&(b.bit_offset, &b.name)));
+    fields.dedup();
+    fields
+}
+
+/// Scans AML byte code for `OperationRegion` and `Field` definitions, keeping the fields of
+/// regions in the `EmbeddedControl` space. Anything that does not decode cleanly is skipped,
+/// but a field whose offset does not fit is an error.
+pub fn parse_aml(aml: &[u8]) -> Result<Vec<EcFieldDef>, String> {
+    let mut regions = BTreeMap::new();
+    let mut field_lists = Vec::new();
+    let mut i = 0;
+    while i + 1 < aml.len() {
+        if aml[i] == 0x5b && aml[i + 1] == 0x80 {
+            if let Some((name, space, offset)) = parse_aml_op_region(aml, i + 2) {
+                regions.insert(name, (space, offset));
+            }
+        } else if aml[i] == 0x5b && aml[i + 1] == 0x81 {
+            if let Some((region, fields, end)) = parse_aml_field(aml, i + 2) {
+                field_lists.push((region, fields));
+                i = end;
+                continue;
+            }
+        }
+        i += 1;
+    }
+
+    let mut ec_fields = Vec::new();
+    for (region, fields) in field_lists {
+        if let Some(&(EMBEDDED_CONTROL_REGION_SPACE, region_offset)) = regions.get(&region) {
+            for (name, bit_offset, bit_width) in fields {
+                ec_fields.push(EcFieldDef {
+                    bit_offset: field_bit_offset(&name, &region, region_offset, bit_offset)?,
+                    name,
+                    region: region.clone(),
+                    bit_width,
+                });
+            }
+        }
+    }
+    Ok(sort_fields(ec_fields))
+}
+
+/// Decodes a PkgLength, returning its value and the position after it.
+fn parse_pkg_length(aml: &[u8], pos: usize) -> Option<(u64, usize)> {
+    let lead = *aml.get(pos)?;
+    let follow = (lead >> 6) as usize;
+    if follow == 0 {
+        return Some(((lead & 0x3f) as u64, pos + 1));
+    }
+    let mut value = (lead & 0x0f) as u64;
+    for n in 0..follow {
+        value |= (*aml.get(pos + 1 + n)? as u64) << (4 + 8 * n);
+    }
+    Some((value, pos + 1 + follow))
+}
+
+fn parse_name_seg(aml: &[u8], pos: usize) -> Option<(String, usize)> {
+    let seg = aml.get(pos..pos + 4)?;
+    let lead_ok = seg[0].is_ascii_uppercase() || seg[0] == b'_';
+    let rest_ok = seg[1..]
+        .iter()
+        .all(|&c| c.is_ascii_uppercase() || c.is_ascii_digit() || c == b'_');
+    if lead_ok && rest_ok {
+        Some((String::from_utf8_lossy(seg).into_owned(), pos + 4))
+    } else {
+        None
+    }
+}
+
+/// Decodes a NameString, returning its last segment and the position after it.
+fn parse_name_string(aml: &[u8], mut pos: usize) -> Option<(String, usize)> {
+    while matches!(aml.get(pos), Some(b'\\') | Some(b'^')) {
+        pos += 1;
+    }
+    let count = match *aml.get(pos)? {
+        0x2e => {
+            pos += 1;
+            2
+        }
+        0x2f => {
+            pos += 2;
+            *aml.get(pos - 1)? as usize
+        }
+        _ => 1,
+    };
+    let mut last = None;
+    for _ in 0..count {
+        let (seg, next) = parse_name_seg(aml, pos)?;
+        last = Some(seg);
+        pos = next;
+    }
+    Some((last?, pos))
+}
+
+/// Decodes a constant integer TermArg; regions with computed offsets are not supported.
+fn parse_aml_integer(aml: &[u8], pos: usize) -> Option<(u64, usize)> {
+    let read_le = |len: usize| {
+        let bytes = aml.get(pos + 1..pos + 1 + len)?;
+        let value = bytes.iter().rev().fold(0u64, |acc, &b| acc << 8 | b as u64);
+        Some((value, pos + 1 + len))
+    };
+    match *aml.get(pos)? {
+        0x00 => Some((0, pos + 1)),
+        0x01 => Some((1, pos + 1)),
+        0xff => Some((u64::MAX, pos + 1)),
+        0x0a => read_le(1),
+        0x0b => read_le(2),
+        0x0c => read_le(4),
+        0x0e => read_le(8),
+        _ => None,
+    }
+}
+
+fn parse_aml_op_region(aml: &[u8], pos: usize) -> Option<(String, u8, u64)> {
+    let (name, pos) = parse_name_string(aml, pos)?;
+    let space = *aml.get(pos)?;
+    let (offset, _) = parse_aml_integer(aml, pos + 1)?;
+    Some((name, space, offset))
+}
+
+type AmlFieldList = Vec<(String, u64, u64)>;
+
+fn parse_aml_field(aml: &[u8], pos: usize) -> Option<(String, AmlFieldList, usize)> {
+    let (length, after_length) = parse_pkg_length(aml, pos)?;
+    let end = pos.checked_add(length as usize)?;
+    if length == 0 || end > aml.len() {
+        return None;
+    }
+    let (region, mut pos) = parse_name_string(aml, after_length)?;
+    pos += 1; // FieldFlags
+
+    let mut fields = Vec::new();
+    let mut bit_offset = 0;
+    while pos < end {
+        match aml[pos] {
+            // ReservedField, which is how Offset () is encoded
+            0x00 => {
+                let (width, next) = parse_pkg_length(aml, pos + 1)?;
+                bit_offset += width;
+                pos = next;
+            }
+            // AccessField
+            0x01 => pos += 3,
+            // ExtendedAccessField
+            0x03 => pos += 4,
+            _ => {
+                let (name, next) = parse_name_seg(aml, pos)?;
+                let (width, next) = parse_pkg_length(aml, next)?;
+                fields.push((name, bit_offset, width));
+                bit_offset += width;
+                pos = next;
+            }
+        }
+    }
+    if pos == end {
+        Some((region, fields, end))
+    } else {
+        None
+    }
+}
+
+/// Parses the `OperationRegion` and `Field` declarations of decompiled ASL source.
+pub fn parse_asl(source: &str) -> Result<Vec<EcFieldDef>, String> {
+    let source = strip_asl_comments(source);
+    let mut regions = BTreeMap::new();
+    for args in asl_calls(&source, "OperationRegion") {
+        let args = split_top_level(&args.0);
+        if let [name, space, offset, ..] = args.as_slice() {
+            if *space == "EmbeddedControl" {
+                if let Some(offset) = parse_asl_integer(offset) {
+                    regions.insert(last_name_seg(name).to_string(), offset);
+                }
+            }
+        }
+    }
+
+    let mut fields = Vec::new();
+    for (args, body) in asl_calls(&source, "Field") {
+        let region = match split_top_level(&args).first() {
+            Some(region) => last_name_seg(region).to_string(),
+            None => continue,
+        };
+        let region_offset = match (regions.get(&region), body) {
+            (Some(&offset), Some(_)) => offset,
+            _ => continue,
+        };
+        let items = split_top_level(body.unwrap_or_default());
+        let mut bit_offset = 0;
+        let mut items = items.into_iter();
+        while let Some(item) = items.next() {
+            if let Some(offset) = item.strip_prefix("Offset") {
+                match parse_asl_integer(offset.trim().trim_start_matches('(').trim_end_matches(')'))
+                {
+                    Some(offset) => {
+                        bit_offset = offset.checked_mul(8).ok_or_else(|| {
+                            format!("Offset ({:#x}) in region {} is too large", offset, region)
+                        })?
+                    }
+                    None => break,
+                }
+            } else if item.starts_with("AccessAs") || item.starts_with("Connection") {
+                continue;
+            } else {
+                let width = match items.next().and_then(parse_asl_integer) {
+                    Some(width) => width,
+                    None => break,
+                };
+                if !item.is_empty() {
+                    fields.push(EcFieldDef {
+                        name: item.to_string(),
+                        region: region.clone(),
+                        bit_offset: field_bit_offset(item, &region, region_offset, bit_offset)?,
+                        bit_width: width,
+                    });
+                }
+                bit_offset = bit_offset
+                    .checked_add(width)
+                    .ok_or_else(|| format!("{} in region {} is too wide", item, region))?;
+            }
+        }
+    }
+    Ok(sort_fields(fields))
+}
+
+fn strip_asl_comments(source: &str) -> String {
+    let mut out = String::with_capacity(source.len());
+    let mut rest = source;
+    while let Some(start) = rest.find("/*").into_iter().chain(rest.find("//")).min() {
+        out.push_str(&rest[..start]);
+        rest = if rest[start..].starts_with("/*") {
+            rest[start..]
+                .find("*/")
+                .map_or("", |end| &rest[start + end + 2..])
+        } else {
+            rest[start..]
+                .find('\n')
+                .map_or("", |end| &rest[start + end..])
+        };
+    }
+    out.push_str(rest);
+    out
+}
+
+/// Finds every `keyword (args) { body }`, returning the args and, if present, the body.
+fn asl_calls<'a>(source: &'a str, keyword: &str) -> Vec<(String, Option<&'a str>)> {
+    let mut calls = Vec::new();
+    let mut search_from = 0;
+    while let Some(found) = source[search_from..].find(keyword) {
+        let start = search_from + found;
+        search_from = start + keyword.len();
+        let preceded_by_ident = source[..start]
+            .chars()
+            .next_back()
+            .is_some_and(|c| c.is_alphanumeric() || c == '_');
+        let rest = source[search_from..].trim_start();
+        if preceded_by_ident || !rest.starts_with('(') {
+            continue;
+        }
+        let rest_start = source.len() - rest.len();
+        let args_end = match rest.find(')') {
+            Some(end) => rest_start + end,
+            None => break,
+        };
+        let args = source[rest_start + 1..args_end].to_string();
+        let after_args = source[args_end + 1..].trim_start();
+        let body = after_args
+            .strip_prefix('{')
+            .and_then(|body| body.find('}').map(|end| &body[..end]));
+        calls.push((args, body));
+    }
+    calls
+}
+
+/// Splits on commas that are not inside parentheses, trimming each part.
+fn split_top_level(s: &str) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let mut depth = 0;
+    let mut start = 0;
+    for (i, c) in s.char_indices() {
+        match c {
+            '(' => depth += 1,
+            ')' => depth -= 1,
+            ',' if depth == 0 => {
+                parts.push(s[start..i].trim());
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    let last = s[start..].trim();
+    if !last.is_empty() {
+        parts.push(last);
+    }
+    parts
+}
+
+fn last_name_seg(name: &str) -> &str {
+    name.rsplit('.')
+        .next()
+        .unwrap_or(name)
+        .trim_start_matches(['\\', '^'])
+}
+
+fn parse_asl_integer(s: &str) -> Option<u64> {
+    match s.trim() {
+        "Zero" => Some(0),
+        "One" => Some(1),
+        s => match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+            Some(hex) => u64::from_str_radix(hex, 16).ok(),
+            None => s.parse().ok(),
+        },
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FieldMapFormat {
+    Table,
+    Csv,
+    /// A register map in the `Register 0x40 SW2S 1, ACCC 1` line format, for `--register-map`.
+    Map,
+    /// The same register map as TOML.
+    Toml,
+}
+
+impl FromStr for FieldMapFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "table" => Ok(FieldMapFormat::Table),
+            "csv" => Ok(FieldMapFormat::Csv),
+            "map" => Ok(FieldMapFormat::Map),
+            "toml" => Ok(FieldMapFormat::Toml),
+            _ => Err(format!(
+                "unknown format '{}', expected table, csv, map or toml",
+                s
+            )),
+        }
+    }
+}
+
+pub fn render(fields: &[EcFieldDef], format: FieldMapFormat) -> String {
+    let mut out = String::new();
+    match format {
+        FieldMapFormat::Table => {
+            out.push_str("name  register  bit  width  region\n");
+            for f in fields {
+                writeln!(
+                    out,
+                    "{:<4}  0x{:02X}      {:<3}  {:<5}  {}",
+                    f.name,
+                    f.register_offset(),
+                    f.first_bit(),
+                    f.bit_width,
+                    f.region
+                )
+                .unwrap();
+            }
+        }
+        FieldMapFormat::Csv => {
+            out.push_str("name,register,bit,width,region\n");
+            for f in fields {
+                writeln!(
+                    out,
+                    "{},{:#04x},{},{},{}",
+                    f.name,
+                    f.register_offset(),
+                    f.first_bit(),
+                    f.bit_width,
+                    f.region
+                )
+                .unwrap();
+            }
+        }
+        FieldMapFormat::Map | FieldMapFormat::Toml => {
+            let (map, left_out) = RegisterMap::from_dsdt(fields);
+            for reason in left_out {
+                writeln!(out, "# Left out: {}", reason).unwrap();
+            }
+            out.push_str(&if format == FieldMapFormat::Map {
+                map.to_lines()
+            } else {
+                map.to_toml()
+            });
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fixture(name: &str) -> String {
+        format!("{}/fixtures/dsdt/{}", env!("CARGO_MANIFEST_DIR"), name)
+    }
+
+    fn layout(fields: &[EcFieldDef]) -> Vec<(&str, u64, u64, u64)> {
+        fields
+            .iter()
+            .map(|f| {
+                (
+                    f.name.as_str(),
+                    f.register_offset(),
+                    f.first_bit(),
+                    f.bit_width,
+                )
+            })
+            .collect()
+    }
+
+    const EXPECTED: &[(&str, u64, u64, u64)] = &[
+        ("SW2S", 0x40, 0, 1),
+        ("ACCC", 0x40, 3, 1),
+        ("TRPM", 0x40, 4, 1),
+        ("CTYP", 0x53, 0, 8),
+        ("RPM1", 0xb2, 0, 8),
+        ("RPM2", 0xb3, 0, 8),
+    ];
+
+    #[test]
+    fn extracts_ec_fields_from_aml() {
+        let aml = fs::read(fixture("ec-fields.aml")).unwrap();
+        assert!(is_aml_table(&aml));
+        let fields = sort_fields(parse_aml(&aml[ACPI_TABLE_HEADER_LEN..]).unwrap());
+        assert_eq!(layout(&fields), EXPECTED);
+        assert!(fields.iter().all(|f| f.region == "ERAM"));
+    }
+
+    #[test]
+    fn extracts_ec_fields_from_asl() {
+        let asl = fs::read_to_string(fixture("ec-fields.dsl")).unwrap();
+        let fields = sort_fields(parse_asl(&asl).unwrap());
+        assert_eq!(layout(&fields), EXPECTED);
+        assert!(fields.iter().all(|f| f.region == "ERAM"));
+    }
+
+    #[test]
+    fn loads_either_form() {
+        let from_aml = load(fixture("ec-fields.aml")).unwrap();
+        let from_asl = load(fixture("ec-fields.dsl")).unwrap();
+        assert_eq!(layout(&from_aml), EXPECTED);
+        assert_eq!(from_aml, from_asl);
+    }
+
+    #[test]
+    fn rejects_fields_past_the_end_of_the_address_space() {
+        // OperationRegion (ERAM, EmbeddedControl, Ones, 0xFF) with Field (ERAM) { RPM1, 8 }
+        let mut aml = vec![0x5b, 0x80];
+        aml.extend_from_slice(b"ERAM\x03\xff\x0a\xff");
+        aml.extend_from_slice(&[0x5b, 0x81, 0x0b]);
+        aml.extend_from_slice(b"ERAM\x01RPM1\x08");
+        let error = parse_aml(&aml).unwrap_err();
+        assert!(error.starts_with("RPM1 in region ERAM"), "{}", error);
+        aml[7] = 0x00;
+        assert_eq!(parse_aml(&aml).unwrap()[0].bit_offset, 0);
+
+        for asl in [
+            "OperationRegion (ERAM, EmbeddedControl, 0xFFFFFFFFFFFFFFFF, 0xFF)\n\
+             Field (ERAM, ByteAcc, Lock, Preserve) { RPM1, 8 }",
+            "OperationRegion (ERAM, EmbeddedControl, Zero, 0xFF)\n\
+             Field (ERAM, ByteAcc, Lock, Preserve) { Offset (0x2000000000000000), RPM1, 8 }",
+            "OperationRegion (ERAM, EmbeddedControl, Zero, 0xFF)\n\
+             Field (ERAM, ByteAcc, Lock, Preserve) { BUF0, 0xFFFFFFFFFFFFFFFF, RPM1, 8 }",
+        ] {
+            assert!(parse_asl(asl).is_err(), "{}", asl);
+        }
+    }
+}
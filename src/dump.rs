@@ -0,0 +1,233 @@
+use std::fmt::Write;
+use std::str::FromStr;
+
+use crate::ec::EC_REGISTER_COUNT;
+use crate::regmap::RegisterMap;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DumpFormat {
+    /// Hex table with an ASCII column.
+    Table,
+    /// The table printed by `ec-probe dump`.
+    EcProbe,
+    /// One `register,value` line per register.
+    Csv,
+    Json,
+    /// The value of every named register and field in the register map.
+    Named,
+}
+
+impl FromStr for DumpFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "table" => Ok(DumpFormat::Table),
+            "ec-probe" => Ok(DumpFormat::EcProbe),
+            "csv" => Ok(DumpFormat::Csv),
+            "json" => Ok(DumpFormat::Json),
+            "named" => Ok(DumpFormat::Named),
+            _ => Err(format!(
+                "unknown dump format '{}', expected table, ec-probe, csv, json or named",
+                s
+            )),
+        }
+    }
+}
+
+pub fn render(
+    registers: &[u8; EC_REGISTER_COUNT as usize],
+    format: DumpFormat,
+    register_map: &RegisterMap,
+) -> String {
+    match format {
+        DumpFormat::Table => render_table(registers),
+        DumpFormat::EcProbe => render_ec_probe(registers),
+        DumpFormat::Csv => render_csv(registers),
+        DumpFormat::Json => render_json(registers),
+        DumpFormat::Named => render_named(registers, register_map),
+    }
+}
+
+fn render_table(registers: &[u8]) -> String {
+    let mut out = String::from("    ");
+    for column in 0..16 {
+        write!(out, " {:02X}", column).unwrap();
+    }
+    out.push('\n');
+    for (row, values) in registers.chunks(16).enumerate() {
+        write!(out, "{:02X}: ", row * 16).unwrap();
+        for value in values {
+            write!(out, " {:02X}", value).unwrap();
+        }
+        out.push_str("  |");
+        out.extend(values.iter().map(|&v| {
+            if v.is_ascii_graphic() || v == b' ' {
+                v as char
+            } else {
+                '.'
+            }
+        }));
+        out.push_str("|\n");
+    }
+    out
+}
+
+fn render_ec_probe(registers: &[u8]) -> String {
+    let mut out = String::from("   |");
+    for column in 0..16 {
+        write!(out, " {:02X}", column).unwrap();
+    }
+    out.push_str("\n---|");
+    out.push_str(&"-".repeat(48));
+    out.push('\n');
+    for (row, values) in registers.chunks(16).enumerate() {
+        write!(out, "{:02X} |", row * 16).unwrap();
+        for value in values {
+            write!(out, " {:02X}", value).unwrap();
+        }
+        out.push('\n');
+    }
+    out
+}
+
+fn render_csv(registers: &[u8]) -> String {
+    let mut out = String::from("register,value\n");
+    for (register, value) in registers.iter().enumerate() {
+        writeln!(out, "{:#04x},{:#04x}", register, value).unwrap();
+    }
+    out
+}
+
+fn render_json(registers: &[u8]) -> String {
+    let values: Vec<String> = registers.iter().map(u8::to_string).collect();
+    format!("{{\"registers\":[{}]}}\n", values.join(","))
+}
+
+fn render_named(registers: &[u8], register_map: &RegisterMap) -> String {
+    let width = register_map
+        .iter()
+        .map(|(name, _)| name.len())
+        .max()
+        .unwrap_or(0)
+        .max("name".len());
+    let mut out = format!("{:<width$}  register  bits  value\n", "name", width = width);
+    for (name, def) in register_map.iter() {
+        let value = def.decode_from(registers);
+        let bits = if def.is_whole_register() {
+            String::new()
+        } else {
+            format!("{}:{}", def.bit, def.bits)
+        };
+        writeln!(
+            out,
+            "{:<width$}  0x{:02X}      {:<4}  {:#04x} {}",
+            name,
+            def.offset,
+            bits,
+            value,
+            value,
+            width = width
+        )
+        .unwrap();
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Every register holding its own offset, so that each value says where it was printed.
+    fn image() -> [u8; EC_REGISTER_COUNT as usize] {
+        let mut registers = [0u8; EC_REGISTER_COUNT as usize];
+        for (i, value) in registers.iter_mut().enumerate() {
+            *value = i as u8;
+        }
+        registers
+    }
+
+    fn dump(format: &str) -> String {
+        let format = format.parse().unwrap();
+        let map = RegisterMap::parse_lines(
+            "ReadRegister 0xb2 RPM1 8, RPM2 8\nRegister 0x40 SW2S 1, 2, ACCC 1\nReadRegister 0x48 BTSN 16",
+        )
+        .unwrap();
+        render(&image(), format, &map)
+    }
+
+    #[test]
+    fn renders_a_table_with_ascii() {
+        let out = dump("table");
+        let lines: Vec<&str> = out.lines().collect();
+        assert_eq!(lines.len(), 17);
+        assert_eq!(
+            lines[0],
+            "     00 01 02 03 04 05 06 07 08 09 0A 0B 0C 0D 0E 0F"
+        );
+        assert_eq!(
+            lines[1],
+            "00:  00 01 02 03 04 05 06 07 08 09 0A 0B 0C 0D 0E 0F  |................|"
+        );
+        assert_eq!(
+            lines[3],
+            "20:  20 21 22 23 24 25 26 27 28 29 2A 2B 2C 2D 2E 2F  | !\"#$%&'()*+,-./|"
+        );
+        assert_eq!(
+            lines[8],
+            "70:  70 71 72 73 74 75 76 77 78 79 7A 7B 7C 7D 7E 7F  |pqrstuvwxyz{|}~.|"
+        );
+        assert_eq!(
+            lines[16],
+            "F0:  F0 F1 F2 F3 F4 F5 F6 F7 F8 F9 FA FB FC FD FE FF  |................|"
+        );
+    }
+
+    #[test]
+    fn renders_the_ec_probe_table() {
+        let out = dump("ec-probe");
+        let lines: Vec<&str> = out.lines().collect();
+        assert_eq!(lines.len(), 18);
+        assert_eq!(
+            lines[..3],
+            [
+                "   | 00 01 02 03 04 05 06 07 08 09 0A 0B 0C 0D 0E 0F",
+                "---|------------------------------------------------",
+                "00 | 00 01 02 03 04 05 06 07 08 09 0A 0B 0C 0D 0E 0F",
+            ]
+        );
+        assert_eq!(
+            lines[17],
+            "F0 | F0 F1 F2 F3 F4 F5 F6 F7 F8 F9 FA FB FC FD FE FF"
+        );
+    }
+
+    #[test]
+    fn renders_csv_and_json() {
+        let csv = dump("csv");
+        let lines: Vec<&str> = csv.lines().collect();
+        assert_eq!(lines.len(), 257);
+        assert_eq!(lines[..3], ["register,value", "0x00,0x00", "0x01,0x01"]);
+        assert_eq!(lines[256], "0xff,0xff");
+
+        let values: Vec<String> = (0..256).map(|v| v.to_string()).collect();
+        assert_eq!(
+            dump("json"),
+            format!("{{\"registers\":[{}]}}\n", values.join(","))
+        );
+    }
+
+    #[test]
+    fn renders_named_registers_and_fields() {
+        assert_eq!(
+            dump("named"),
+            "name  register  bits  value\n\
+             ACCC  0x40      3:1   0x00 0\n\
+             BTSN  0x48      0:16  0x4948 18760\n\
+             RPM1  0xB2            0xb2 178\n\
+             RPM2  0xB3            0xb3 179\n\
+             SW2S  0x40      0:1   0x00 0\n"
+        );
+        assert!("hex".parse::<DumpFormat>().is_err());
+    }
+}
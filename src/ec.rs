@@ -0,0 +1,548 @@
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::prelude::*;
+use std::os::unix::prelude::FileExt;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use derive_more::Display;
+
+pub mod acpi_call;
+pub mod field;
+pub mod port;
+use acpi_call::{AcpiCall, AcpiCallEc};
+use port::{DevPort, PortIoEc, SimulatedEcPorts};
+
+pub const EMBEDDED_CONTROL_SYS_FILE: &str = "/sys/kernel/debug/ec/ec0/io";
+pub const EC_REGISTER_COUNT: u64 = 256;
+pub const DEFAULT_EC_BACKEND: &str = "sysfs";
+
+/// Something that holds the 256 byte register space of an ACPI Embedded Controller.
+pub trait EcBackend: Send + Sync {
+    fn read_register(&self, register_offset: u64) -> io::Result<u8>;
+    fn write_register(&self, register_offset: u64, value: u8) -> io::Result<()>;
+
+    /// Fills `buf` from consecutive registers starting at `first_register_offset`.
+    fn read_registers(&self, first_register_offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        check_register_range(first_register_offset, buf.len())?;
+        for (register_offset, value) in (first_register_offset..).zip(buf.iter_mut()) {
+            *value = self.read_register(register_offset)?;
+        }
+        Ok(())
+    }
+
+    /// Applies the writes of `transaction` in order, stopping at the first failure.
+    /// Backends that can lock the device hold it for the whole transaction.
+    fn apply(&self, transaction: &EcTransaction) -> io::Result<()> {
+        transaction.check()?;
+        for (i, &(register_offset, value)) in transaction.writes().iter().enumerate() {
+            self.write_register(register_offset, value)
+                .map_err(|e| transaction.failed_at(i, e))?;
+        }
+        Ok(())
+    }
+}
+
+/// Reads the whole register space in one go.
+pub fn read_all_registers(ec: &dyn EcBackend) -> io::Result<[u8; EC_REGISTER_COUNT as usize]> {
+    let mut registers = [0u8; EC_REGISTER_COUNT as usize];
+    ec.read_registers(0, &mut registers)?;
+    Ok(registers)
+}
+
+/// An ordered batch of register writes.
+#[derive(Clone, Debug, Default)]
+pub struct EcTransaction {
+    writes: Vec<(u64, u8)>,
+}
+
+impl EcTransaction {
+    pub fn new() -> EcTransaction {
+        EcTransaction::default()
+    }
+
+    pub fn write(&mut self, register_offset: u64, value: u8) -> &mut EcTransaction {
+        self.writes.push((register_offset, value));
+        self
+    }
+
+    pub fn writes(&self) -> &[(u64, u8)] {
+        &self.writes
+    }
+
+    /// Rejects the whole transaction up front if any write is out of range.
+    fn check(&self) -> io::Result<()> {
+        self.writes
+            .iter()
+            .try_for_each(|&(register_offset, _)| check_register_offset(register_offset))
+    }
+
+    fn failed_at(&self, index: usize, e: io::Error) -> io::Error {
+        let (register_offset, value) = self.writes[index];
+        io::Error::new(
+            e.kind(),
+            format!(
+                "write {} of {} ({:#04x} to register {:#x}) failed, earlier writes were applied: {}",
+                index + 1,
+                self.writes.len(),
+                value,
+                register_offset,
+                e
+            ),
+        )
+    }
+}
+
+/// How hard to try before concluding that the EC ignored a write.
+#[derive(Clone, Copy, Debug)]
+pub struct WriteVerification {
+    /// Read the register back after writing it.
+    pub read_back: bool,
+    /// Further attempts after the first write does not stick.
+    pub retries: u32,
+    /// Wait before the first retry, doubled for each one after.
+    pub backoff: Duration,
+}
+
+impl Default for WriteVerification {
+    fn default() -> WriteVerification {
+        WriteVerification {
+            read_back: true,
+            retries: 3,
+            backoff: Duration::from_millis(50),
+        }
+    }
+}
+
+#[derive(Display, Debug)]
+pub enum EcWriteError {
+    #[display(fmt = "{}", _0)]
+    Io(io::Error),
+    #[display(
+        fmt = "EC register {:#x} reads {:#04x} instead of {:#04x} after {} attempts",
+        register_offset,
+        observed,
+        expected,
+        attempts
+    )]
+    Mismatch {
+        register_offset: u64,
+        expected: u8,
+        observed: u8,
+        attempts: u32,
+    },
+}
+
+impl std::error::Error for EcWriteError {}
+
+impl From<io::Error> for EcWriteError {
+    fn from(e: io::Error) -> EcWriteError {
+        EcWriteError::Io(e)
+    }
+}
+
+/// Writes `value` and reads it back, retrying with backoff while the EC does not take it.
+/// The backoff sleeps without holding up the runtime.
+pub async fn write_verified(
+    ec: &dyn EcBackend,
+    register_offset: u64,
+    value: u8,
+    verification: &WriteVerification,
+) -> Result<(), EcWriteError> {
+    let mut backoff = verification.backoff;
+    let mut attempts = 0;
+    loop {
+        ec.write_register(register_offset, value)?;
+        attempts += 1;
+        if !verification.read_back {
+            return Ok(());
+        }
+        let observed = ec.read_register(register_offset)?;
+        if observed == value {
+            return Ok(());
+        }
+        if attempts > verification.retries {
+            return Err(EcWriteError::Mismatch {
+                register_offset,
+                expected: value,
+                observed,
+                attempts,
+            });
+        }
+        tokio::time::sleep(backoff).await;
+        backoff *= 2;
+    }
+}
+
+fn check_register_offset(register_offset: u64) -> io::Result<()> {
+    check_register_range(register_offset, 1)
+}
+
+fn check_register_range(first_register_offset: u64, len: usize) -> io::Result<()> {
+    match first_register_offset.checked_add(len as u64) {
+        Some(end) if end <= EC_REGISTER_COUNT => Ok(()),
+        Some(end) => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("EC register offset {:#x} is out of range", end - 1),
+        )),
+        None => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "EC register range of {} from {:#x} is out of range",
+                len, first_register_offset
+            ),
+        )),
+    }
+}
+
+/// Opens `path` for reading and writing, falling back to read-only so that inspecting
+/// the EC still works where writes are not permitted.
+fn open_register_file(path: &Path) -> io::Result<File> {
+    match OpenOptions::new().read(true).write(true).open(path) {
+        Err(e) if e.kind() == io::ErrorKind::PermissionDenied => File::open(path),
+        result => result,
+    }
+}
+
+/// Parses a register offset or value written either in hex (`0xb2`) or decimal.
+pub fn parse_register_number(s: &str) -> Result<u64, std::num::ParseIntError> {
+    match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        Some(hex) => u64::from_str_radix(hex, 16),
+        None => s.parse(),
+    }
+}
+
+/// The debugfs interface exposed by the `ec_sys` kernel module (needs `write_support=1`).
+/// The file is opened once and kept for the lifetime of the backend.
+pub struct SysfsEc {
+    file: Mutex<File>,
+}
+
+impl SysfsEc {
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<SysfsEc> {
+        Ok(SysfsEc {
+            file: Mutex::new(open_register_file(path.as_ref())?),
+        })
+    }
+}
+
+impl EcBackend for SysfsEc {
+    fn read_register(&self, register_offset: u64) -> io::Result<u8> {
+        let mut buf = [0u8; 1];
+        self.read_registers(register_offset, &mut buf)?;
+        Ok(buf[0])
+    }
+
+    fn write_register(&self, register_offset: u64, value: u8) -> io::Result<()> {
+        self.apply(EcTransaction::new().write(register_offset, value))
+    }
+
+    fn read_registers(&self, first_register_offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        check_register_range(first_register_offset, buf.len())?;
+        self.file
+            .lock()
+            .unwrap()
+            .read_exact_at(buf, first_register_offset)
+    }
+
+    fn apply(&self, transaction: &EcTransaction) -> io::Result<()> {
+        transaction.check()?;
+        let mut f = self.file.lock().unwrap();
+        for (i, &(register_offset, value)) in transaction.writes().iter().enumerate() {
+            f.write_all_at(&[value], register_offset)
+                .map_err(|e| transaction.failed_at(i, e))?;
+        }
+        f.flush()
+    }
+}
+
+/// A register space that only lives in memory, for running without an EC.
+pub struct MemoryEc {
+    registers: Mutex<[u8; EC_REGISTER_COUNT as usize]>,
+}
+
+impl MemoryEc {
+    pub fn new() -> MemoryEc {
+        MemoryEc::with_registers([0u8; EC_REGISTER_COUNT as usize])
+    }
+
+    pub fn with_registers(registers: [u8; EC_REGISTER_COUNT as usize]) -> MemoryEc {
+        MemoryEc {
+            registers: Mutex::new(registers),
+        }
+    }
+}
+
+impl Default for MemoryEc {
+    fn default() -> MemoryEc {
+        MemoryEc::new()
+    }
+}
+
+impl EcBackend for MemoryEc {
+    fn read_register(&self, register_offset: u64) -> io::Result<u8> {
+        check_register_offset(register_offset)?;
+        Ok(self.registers.lock().unwrap()[register_offset as usize])
+    }
+
+    fn write_register(&self, register_offset: u64, value: u8) -> io::Result<()> {
+        check_register_offset(register_offset)?;
+        self.registers.lock().unwrap()[register_offset as usize] = value;
+        Ok(())
+    }
+
+    fn read_registers(&self, first_register_offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        check_register_range(first_register_offset, buf.len())?;
+        let first = first_register_offset as usize;
+        buf.copy_from_slice(&self.registers.lock().unwrap()[first..first + buf.len()]);
+        Ok(())
+    }
+
+    fn apply(&self, transaction: &EcTransaction) -> io::Result<()> {
+        transaction.check()?;
+        let mut registers = self.registers.lock().unwrap();
+        for &(register_offset, value) in transaction.writes() {
+            registers[register_offset as usize] = value;
+        }
+        Ok(())
+    }
+}
+
+/// A plain 256 byte file laid out like the debugfs `io` file, created zero-filled if missing.
+pub struct FileEc {
+    file: Mutex<File>,
+}
+
+impl FileEc {
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<FileEc> {
+        let f = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)?;
+        if f.metadata()?.len() < EC_REGISTER_COUNT {
+            f.set_len(EC_REGISTER_COUNT)?;
+        }
+        Ok(FileEc {
+            file: Mutex::new(f),
+        })
+    }
+}
+
+impl EcBackend for FileEc {
+    fn read_register(&self, register_offset: u64) -> io::Result<u8> {
+        let mut buf = [0u8; 1];
+        self.read_registers(register_offset, &mut buf)?;
+        Ok(buf[0])
+    }
+
+    fn write_register(&self, register_offset: u64, value: u8) -> io::Result<()> {
+        self.apply(EcTransaction::new().write(register_offset, value))
+    }
+
+    fn read_registers(&self, first_register_offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        check_register_range(first_register_offset, buf.len())?;
+        self.file
+            .lock()
+            .unwrap()
+            .read_exact_at(buf, first_register_offset)
+    }
+
+    fn apply(&self, transaction: &EcTransaction) -> io::Result<()> {
+        transaction.check()?;
+        let f = self.file.lock().unwrap();
+        for (i, &(register_offset, value)) in transaction.writes().iter().enumerate() {
+            f.write_all_at(&[value], register_offset)
+                .map_err(|e| transaction.failed_at(i, e))?;
+        }
+        f.sync_data()
+    }
+}
+
+/// Opens a backend from a spec such as `sysfs`, `sysfs:/path/to/io`, `port`,
+/// `acpi-call:0xb2=RPM1,0x89=/FSSP`, `memory` or `file:/path`.
+pub fn open_backend(spec: &str) -> io::Result<Box<dyn EcBackend>> {
+    let (kind, arg) = match spec.split_once(':') {
+        Some((kind, arg)) => (kind, Some(arg)),
+        None => (spec, None),
+    };
+    match (kind, arg) {
+        ("sysfs", None) => Ok(Box::new(SysfsEc::open(EMBEDDED_CONTROL_SYS_FILE)?)),
+        ("sysfs", Some(path)) => Ok(Box::new(SysfsEc::open(path)?)),
+        ("port", None) => Ok(Box::new(PortIoEc::new(DevPort::open(port::DEV_PORT_FILE)?))),
+        ("port", Some(path)) => Ok(Box::new(PortIoEc::new(DevPort::open(path)?))),
+        ("port-sim", None) => Ok(Box::new(PortIoEc::new(SimulatedEcPorts::new(
+            MemoryEc::new(),
+        )))),
+        ("acpi-call", bindings) => Ok(Box::new(
+            AcpiCallEc::new(AcpiCall::default(), acpi_call::DEFAULT_EC_DEVICE)
+                .with_bindings(bindings.unwrap_or(""))?,
+        )),
+        ("memory", None) => Ok(Box::new(MemoryEc::new())),
+        ("file", Some(path)) => Ok(Box::new(FileEc::open(path)?)),
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("unknown EC backend '{}'", spec),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    /// Takes every write but reads back a fixed value for `register_offset`, like a register
+    /// the firmware keeps overwriting.
+    struct StubbornEc {
+        registers: MemoryEc,
+        register_offset: u64,
+        reads: u8,
+    }
+
+    impl EcBackend for StubbornEc {
+        fn read_register(&self, register_offset: u64) -> io::Result<u8> {
+            if register_offset == self.register_offset {
+                Ok(self.reads)
+            } else {
+                self.registers.read_register(register_offset)
+            }
+        }
+
+        fn write_register(&self, register_offset: u64, value: u8) -> io::Result<()> {
+            self.registers.write_register(register_offset, value)
+        }
+    }
+
+    fn quick_verification(retries: u32) -> WriteVerification {
+        WriteVerification {
+            read_back: true,
+            retries,
+            backoff: Duration::from_millis(1),
+        }
+    }
+
+    #[tokio::test]
+    async fn verified_writes_that_stick() {
+        let ec = MemoryEc::new();
+        write_verified(&ec, 0xf4, 0x47, &quick_verification(0))
+            .await
+            .unwrap();
+        assert_eq!(ec.read_register(0xf4).unwrap(), 0x47);
+    }
+
+    #[tokio::test]
+    async fn gives_up_on_writes_that_do_not_stick() {
+        let ec = StubbornEc {
+            registers: MemoryEc::new(),
+            register_offset: 0x89,
+            reads: 0x12,
+        };
+        match write_verified(&ec, 0x89, 0x04, &quick_verification(2)).await {
+            Err(EcWriteError::Mismatch {
+                expected,
+                observed,
+                attempts,
+                ..
+            }) => assert_eq!((expected, observed, attempts), (0x04, 0x12, 3)),
+            other => panic!("expected a mismatch, got {:?}", other),
+        }
+        let unverified = WriteVerification {
+            read_back: false,
+            ..quick_verification(2)
+        };
+        assert!(write_verified(&ec, 0x89, 0x04, &unverified).await.is_ok());
+    }
+
+    #[test]
+    fn checks_register_ranges() {
+        assert!(check_register_range(0, 256).is_ok());
+        assert!(check_register_range(0xff, 1).is_ok());
+        assert!(check_register_range(0xff, 0).is_ok());
+        let e = check_register_range(0xff, 2).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+        assert!(e.to_string().contains("0x100"));
+    }
+
+    #[test]
+    fn rejects_ranges_that_overflow() {
+        let e = check_register_range(u64::MAX, 2).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+        assert!(MemoryEc::new()
+            .read_registers(u64::MAX, &mut [0u8; 4])
+            .is_err());
+    }
+
+    #[test]
+    fn keeps_file_registers_at_their_offsets() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("ec.bin");
+        let ec = FileEc::open(&path).unwrap();
+        assert_eq!(fs::read(&path).unwrap(), [0u8; 256]);
+        ec.write_register(0x93, 0x14).unwrap();
+        ec.apply(EcTransaction::new().write(0xb2, 0x34).write(0xb3, 0x12))
+            .unwrap();
+        let mut buf = [0u8; 3];
+        ec.read_registers(0xb1, &mut buf).unwrap();
+        assert_eq!(buf, [0x00, 0x34, 0x12]);
+        assert!(ec.read_registers(0xff, &mut buf).is_err());
+        assert!(ec.write_register(0x100, 0).is_err());
+        drop(ec);
+
+        let bytes = fs::read(&path).unwrap();
+        assert_eq!((bytes[0x93], bytes[0xb2], bytes[0xb3]), (0x14, 0x34, 0x12));
+        // A short image is padded out rather than refused.
+        fs::write(&path, [0xaa; 16]).unwrap();
+        let ec = FileEc::open(&path).unwrap();
+        assert_eq!(ec.read_register(0x0f).unwrap(), 0xaa);
+        assert_eq!(ec.read_register(0xff).unwrap(), 0x00);
+    }
+
+    #[test]
+    fn opens_backends_by_spec() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("ec.bin");
+        let spec = |kind: &str| format!("{}:{}", kind, path.display());
+
+        let ec = open_backend(&spec("file")).unwrap();
+        ec.write_register(0x58, 45).unwrap();
+        // The debugfs backend reads the same layout.
+        assert_eq!(
+            open_backend(&spec("sysfs"))
+                .unwrap()
+                .read_register(0x58)
+                .unwrap(),
+            45
+        );
+        for spec in ["memory", "port-sim"] {
+            let ec = open_backend(spec).unwrap();
+            ec.write_register(0x58, 45).unwrap();
+            assert_eq!(ec.read_register(0x58).unwrap(), 45, "{}", spec);
+        }
+        let acpi_call = open_backend("acpi-call:0xb2=RPM1").unwrap();
+        assert_eq!(
+            acpi_call.read_register(0x58).unwrap_err().kind(),
+            io::ErrorKind::Unsupported
+        );
+
+        let missing = dir.path().join("missing").display().to_string();
+        for spec in [format!("sysfs:{}", missing), format!("port:{}", missing)] {
+            assert_eq!(
+                open_backend(&spec).err().unwrap().kind(),
+                io::ErrorKind::NotFound,
+                "{}",
+                spec
+            );
+        }
+        for spec in ["eeprom", "memory:4", "file", "port-sim:x", "acpi-call:0xb2"] {
+            assert_eq!(
+                open_backend(spec).err().unwrap().kind(),
+                io::ErrorKind::InvalidInput,
+                "{}",
+                spec
+            );
+        }
+    }
+}
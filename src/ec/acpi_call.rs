@@ -0,0 +1,252 @@
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::fs::{self, OpenOptions};
+use std::io;
+use std::io::prelude::*;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+use super::{check_register_offset, parse_register_number, EcBackend};
+
+pub const ACPI_CALL_FILE: &str = "/proc/acpi/call";
+pub const DEFAULT_EC_DEVICE: &str = "\\_SB.PCI0.LPCB.EC0";
+
+/// Evaluates ACPI methods through the `acpi_call` kernel module.
+pub struct AcpiCall {
+    path: PathBuf,
+    // The module keeps a single result buffer, so a call and its read-back must not interleave.
+    lock: Mutex<()>,
+}
+
+impl AcpiCall {
+    pub fn new<P: Into<PathBuf>>(path: P) -> AcpiCall {
+        AcpiCall {
+            path: path.into(),
+            lock: Mutex::new(()),
+        }
+    }
+
+    /// Calls `method` with integer arguments and returns its integer result.
+    pub fn call(&self, method: &str, args: &[u64]) -> io::Result<u64> {
+        let mut command = method.to_string();
+        for arg in args {
+            command.push_str(&format!(" {:#x}", arg));
+        }
+
+        let _guard = self.lock.lock().unwrap();
+        OpenOptions::new()
+            .write(true)
+            .open(&self.path)?
+            .write_all(command.as_bytes())?;
+        let result = fs::read_to_string(&self.path)?;
+        parse_acpi_call_result(method, &result)
+    }
+}
+
+impl Default for AcpiCall {
+    fn default() -> AcpiCall {
+        AcpiCall::new(ACPI_CALL_FILE)
+    }
+}
+
+fn parse_acpi_call_result(method: &str, result: &str) -> io::Result<u64> {
+    let result = result.trim_matches(|c: char| c == '\0' || c.is_whitespace());
+    if let Some(hex) = result.strip_prefix("0x") {
+        return u64::from_str_radix(hex, 16).map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{} returned malformed integer '{}'", method, result),
+            )
+        });
+    }
+    let kind = if result.starts_with("Error:") {
+        io::ErrorKind::Other
+    } else {
+        io::ErrorKind::InvalidData
+    };
+    Err(io::Error::new(
+        kind,
+        format!("{} did not return an integer: {}", method, result),
+    ))
+}
+
+/// The ACPI methods that stand in for reading and writing one EC register.
+#[derive(Clone, Debug, Default)]
+pub struct AcpiRegisterBinding {
+    pub read_method: Option<String>,
+    pub write_method: Option<String>,
+}
+
+/// Routes register reads and writes through firmware methods, e.g. reading `RPM1` as a
+/// named field or writing through `FSSP`. Registers without a binding are unsupported.
+pub struct AcpiCallEc {
+    acpi_call: AcpiCall,
+    device: String,
+    bindings: BTreeMap<u64, AcpiRegisterBinding>,
+}
+
+impl AcpiCallEc {
+    pub fn new(acpi_call: AcpiCall, device: &str) -> AcpiCallEc {
+        AcpiCallEc {
+            acpi_call,
+            device: device.to_string(),
+            bindings: BTreeMap::new(),
+        }
+    }
+
+    /// Parses bindings such as `0xb2=RPM1,0x89=/FSSP`, i.e. `register=read[/write]`.
+    /// Method names without a leading `\` are relative to the EC device.
+    pub fn with_bindings(mut self, spec: &str) -> io::Result<AcpiCallEc> {
+        for binding in spec.split(',').filter(|b| !b.is_empty()) {
+            let invalid = || {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("invalid acpi_call register binding '{}'", binding),
+                )
+            };
+            let (register, methods) = binding.split_once('=').ok_or_else(invalid)?;
+            let register = parse_register_number(register).map_err(|_| invalid())?;
+            check_register_offset(register)?;
+            let (read, write) = methods.split_once('/').unwrap_or((methods, ""));
+            self.bindings.insert(
+                register,
+                AcpiRegisterBinding {
+                    read_method: Some(read).filter(|m| !m.is_empty()).map(str::to_string),
+                    write_method: Some(write).filter(|m| !m.is_empty()).map(str::to_string),
+                },
+            );
+        }
+        Ok(self)
+    }
+
+    pub fn call(&self, method: &str, args: &[u64]) -> io::Result<u64> {
+        self.acpi_call.call(&self.method_path(method), args)
+    }
+
+    fn method_path(&self, method: &str) -> String {
+        if method.starts_with('\\') {
+            method.to_string()
+        } else {
+            format!("{}.{}", self.device, method)
+        }
+    }
+
+    fn unbound(&self, register_offset: u64, access: &str) -> io::Error {
+        io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!(
+                "no ACPI method bound for {} of EC register {:#x}",
+                access, register_offset
+            ),
+        )
+    }
+}
+
+impl EcBackend for AcpiCallEc {
+    fn read_register(&self, register_offset: u64) -> io::Result<u8> {
+        check_register_offset(register_offset)?;
+        let method = self
+            .bindings
+            .get(&register_offset)
+            .and_then(|b| b.read_method.as_ref())
+            .ok_or_else(|| self.unbound(register_offset, "reads"))?;
+        let value = self.call(method, &[])?;
+        u8::try_from(value).map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "{} returned {:#x}, which does not fit a register",
+                    method, value
+                ),
+            )
+        })
+    }
+
+    fn write_register(&self, register_offset: u64, value: u8) -> io::Result<()> {
+        check_register_offset(register_offset)?;
+        let method = self
+            .bindings
+            .get(&register_offset)
+            .and_then(|b| b.write_method.as_ref())
+            .ok_or_else(|| self.unbound(register_offset, "writes"))?;
+        self.call(method, &[value as u64]).map(|_| ())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_integer_replies() {
+        assert_eq!(parse_acpi_call_result("RPM1", "0x2a\0").unwrap(), 0x2a);
+        assert_eq!(parse_acpi_call_result("RPM1", "0x1F4\n").unwrap(), 500);
+        for (reply, kind) in [
+            ("Error: AE_NOT_FOUND", io::ErrorKind::Other),
+            ("{0x01, 0x02}", io::ErrorKind::InvalidData),
+            ("\"ECRAM\"", io::ErrorKind::InvalidData),
+            ("0xzz", io::ErrorKind::InvalidData),
+            ("not called", io::ErrorKind::InvalidData),
+            ("", io::ErrorKind::InvalidData),
+        ] {
+            let error = parse_acpi_call_result("RPM1", reply).unwrap_err();
+            assert_eq!(error.kind(), kind, "{}", reply);
+            assert!(error.to_string().starts_with("RPM1 "), "{}", error);
+        }
+    }
+
+    #[test]
+    fn calls_the_methods_bound_to_registers() {
+        // A plain file reads back the call instead of a result, which shows what was called.
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let ec = AcpiCallEc::new(AcpiCall::new(file.path()), DEFAULT_EC_DEVICE)
+            .with_bindings("0xb2=RPM1,0x89=/FSSP,0x93=\\_SB.FANR/\\_SB.FANW")
+            .unwrap();
+        let called = |result: io::Result<()>| {
+            let error = result.unwrap_err();
+            assert!(
+                error.to_string().contains("did not return an integer"),
+                "{}",
+                error
+            );
+            let call = fs::read_to_string(file.path()).unwrap();
+            fs::write(file.path(), "").unwrap();
+            call
+        };
+        assert_eq!(
+            called(ec.read_register(0xb2).map(|_| ())),
+            "\\_SB.PCI0.LPCB.EC0.RPM1"
+        );
+        assert_eq!(
+            called(ec.write_register(0x89, 0x42)),
+            "\\_SB.PCI0.LPCB.EC0.FSSP 0x42"
+        );
+        assert_eq!(called(ec.read_register(0x93).map(|_| ())), "\\_SB.FANR");
+        assert_eq!(called(ec.write_register(0x93, 1)), "\\_SB.FANW 0x1");
+
+        for error in [
+            ec.read_register(0x89).unwrap_err(),
+            ec.write_register(0xb2, 0).unwrap_err(),
+            ec.read_register(0x58).unwrap_err(),
+        ] {
+            assert_eq!(error.kind(), io::ErrorKind::Unsupported);
+        }
+        assert_eq!(
+            ec.read_register(0x100).unwrap_err().kind(),
+            io::ErrorKind::InvalidInput
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_bindings() {
+        for spec in ["0xb2", "RPM1=0xb2", "0x100=RPM1"] {
+            let ec = AcpiCallEc::new(AcpiCall::default(), DEFAULT_EC_DEVICE);
+            assert!(ec.with_bindings(spec).is_err(), "{}", spec);
+        }
+        let ec = AcpiCallEc::new(AcpiCall::default(), DEFAULT_EC_DEVICE)
+            .with_bindings("0xb2=RPM1,")
+            .unwrap();
+        assert_eq!(ec.bindings.len(), 1);
+        assert_eq!(ec.bindings[&0xb2].write_method, None);
+    }
+}
@@ -0,0 +1,127 @@
+use std::io;
+
+use serde::{Deserialize, Serialize};
+
+use super::EcBackend;
+
+/// A 16 bit value split over two registers, such as `RPM1`/`RPM2`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RegisterPair {
+    pub low: u64,
+    pub high: u64,
+}
+
+impl RegisterPair {
+    /// Low byte at `first_register_offset`, high byte in the register after it.
+    pub const fn little_endian(first_register_offset: u64) -> RegisterPair {
+        RegisterPair {
+            low: first_register_offset,
+            high: first_register_offset + 1,
+        }
+    }
+
+    pub fn read(&self, ec: &dyn EcBackend) -> io::Result<u16> {
+        let mut buf = [0u8; 2];
+        let (low, high) = if self.high == self.low + 1 {
+            ec.read_registers(self.low, &mut buf)?;
+            (buf[0], buf[1])
+        } else if self.low == self.high + 1 {
+            ec.read_registers(self.high, &mut buf)?;
+            (buf[1], buf[0])
+        } else {
+            (ec.read_register(self.low)?, ec.read_register(self.high)?)
+        };
+        Ok(u16::from_le_bytes([low, high]))
+    }
+}
+
+/// A run of bits within one register, such as the single bit `SW2S` at bit 0 of 0x40.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BitField {
+    pub register_offset: u64,
+    pub first_bit: u8,
+    pub width: u8,
+}
+
+impl BitField {
+    pub const fn new(register_offset: u64, first_bit: u8, width: u8) -> BitField {
+        assert!(width > 0 && first_bit as u16 + width as u16 <= 8);
+        BitField {
+            register_offset,
+            first_bit,
+            width,
+        }
+    }
+
+    pub fn mask(&self) -> u8 {
+        (0xffu16 >> (8 - self.width) << self.first_bit) as u8
+    }
+
+    pub fn max_value(&self) -> u8 {
+        self.mask() >> self.first_bit
+    }
+
+    /// Replaces this field's bits in `register_value`, leaving the others alone.
+    pub fn insert(&self, register_value: u8, value: u8) -> io::Result<u8> {
+        if value > self.max_value() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "{:#x} does not fit in {} bit(s) at bit {} of register {:#x}",
+                    value, self.width, self.first_bit, self.register_offset
+                ),
+            ));
+        }
+        Ok(register_value & !self.mask() | value << self.first_bit)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ec::MemoryEc;
+
+    #[test]
+    fn reads_register_pairs_in_either_order() {
+        let ec = MemoryEc::new();
+        ec.write_register(0xb2, 0x34).unwrap();
+        ec.write_register(0xb3, 0x12).unwrap();
+        ec.write_register(0xc0, 0x56).unwrap();
+        let little = RegisterPair::little_endian(0xb2);
+        assert_eq!((little.low, little.high), (0xb2, 0xb3));
+        assert_eq!(little.read(&ec).unwrap(), 0x1234);
+        let big = RegisterPair {
+            low: 0xb3,
+            high: 0xb2,
+        };
+        assert_eq!(big.read(&ec).unwrap(), 0x3412);
+        let apart = RegisterPair {
+            low: 0xc0,
+            high: 0xb3,
+        };
+        assert_eq!(apart.read(&ec).unwrap(), 0x1256);
+    }
+
+    #[test]
+    fn masks_and_shifts_fields_into_place() {
+        let field = BitField::new(0x40, 3, 2);
+        assert_eq!(field.mask(), 0b0001_1000);
+        assert_eq!(field.max_value(), 3);
+        assert_eq!(BitField::new(0x40, 0, 8).mask(), 0xff);
+        assert_eq!(BitField::new(0x40, 7, 1).mask(), 0x80);
+        for register_value in [0x00, 0xa5, 0xff] {
+            for value in 0..=field.max_value() {
+                let updated = field.insert(register_value, value).unwrap();
+                assert_eq!((updated & field.mask()) >> field.first_bit, value);
+                assert_eq!(updated & !field.mask(), register_value & !field.mask());
+            }
+        }
+        assert!(field.insert(0, 4).is_err());
+    }
+
+    #[test]
+    #[should_panic(expected = "assertion failed")]
+    fn refuses_fields_past_the_end_of_a_register() {
+        BitField::new(0x40, 200, 100);
+    }
+}
@@ -0,0 +1,288 @@
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::os::unix::prelude::FileExt;
+use std::path::Path;
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use super::{check_register_range, EcBackend, EcTransaction, MemoryEc};
+
+pub const DEV_PORT_FILE: &str = "/dev/port";
+pub const EC_DATA_PORT: u64 = 0x62;
+pub const EC_COMMAND_PORT: u64 = 0x66;
+
+const EC_STATUS_OBF: u8 = 0x01;
+const EC_STATUS_IBF: u8 = 0x02;
+const EC_COMMAND_READ: u8 = 0x80;
+const EC_COMMAND_WRITE: u8 = 0x81;
+
+const DEFAULT_TIMEOUT: Duration = Duration::from_millis(500);
+const POLL_INTERVAL: Duration = Duration::from_micros(50);
+
+/// Byte wide access to x86 I/O ports.
+pub trait PortIo: Send {
+    fn inb(&mut self, port: u64) -> io::Result<u8>;
+    fn outb(&mut self, port: u64, value: u8) -> io::Result<()>;
+}
+
+/// I/O ports through the kernel's `/dev/port` device, where the file offset is the port number.
+pub struct DevPort {
+    file: File,
+}
+
+impl DevPort {
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<DevPort> {
+        let file = OpenOptions::new().read(true).write(true).open(path)?;
+        Ok(DevPort { file })
+    }
+}
+
+impl PortIo for DevPort {
+    fn inb(&mut self, port: u64) -> io::Result<u8> {
+        let mut buf = [0u8; 1];
+        self.file.read_exact_at(&mut buf, port)?;
+        Ok(buf[0])
+    }
+
+    fn outb(&mut self, port: u64, value: u8) -> io::Result<()> {
+        self.file.write_all_at(&[value], port)
+    }
+}
+
+/// An EC behind simulated command/status and data ports, following the ACPI EC
+/// read/write protocol. The input buffer reports busy for one status poll after
+/// every byte written, so the polling paths get exercised.
+pub struct SimulatedEcPorts {
+    registers: MemoryEc,
+    state: SimulatedState,
+    output: Option<u8>,
+    input_busy: bool,
+}
+
+enum SimulatedState {
+    Idle,
+    ReadAddress,
+    WriteAddress,
+    WriteValue(u8),
+}
+
+impl SimulatedEcPorts {
+    pub fn new(registers: MemoryEc) -> SimulatedEcPorts {
+        SimulatedEcPorts {
+            registers,
+            state: SimulatedState::Idle,
+            output: None,
+            input_busy: false,
+        }
+    }
+}
+
+impl PortIo for SimulatedEcPorts {
+    fn inb(&mut self, port: u64) -> io::Result<u8> {
+        match port {
+            EC_COMMAND_PORT => {
+                let mut status = 0;
+                if self.output.is_some() {
+                    status |= EC_STATUS_OBF;
+                }
+                if self.input_busy {
+                    status |= EC_STATUS_IBF;
+                    self.input_busy = false;
+                }
+                Ok(status)
+            }
+            EC_DATA_PORT => Ok(self.output.take().unwrap_or(0xff)),
+            _ => Ok(0xff),
+        }
+    }
+
+    fn outb(&mut self, port: u64, value: u8) -> io::Result<()> {
+        self.input_busy = true;
+        match port {
+            EC_COMMAND_PORT => {
+                self.state = match value {
+                    EC_COMMAND_READ => SimulatedState::ReadAddress,
+                    EC_COMMAND_WRITE => SimulatedState::WriteAddress,
+                    _ => SimulatedState::Idle,
+                };
+                Ok(())
+            }
+            EC_DATA_PORT => {
+                self.state = match self.state {
+                    SimulatedState::ReadAddress => {
+                        self.output = Some(self.registers.read_register(value as u64)?);
+                        SimulatedState::Idle
+                    }
+                    SimulatedState::WriteAddress => SimulatedState::WriteValue(value),
+                    SimulatedState::WriteValue(address) => {
+                        self.registers.write_register(address as u64, value)?;
+                        SimulatedState::Idle
+                    }
+                    SimulatedState::Idle => SimulatedState::Idle,
+                };
+                Ok(())
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Reads and writes EC registers with the standard ACPI EC commands over I/O ports,
+/// for kernels without `ec_sys`.
+pub struct PortIoEc<P: PortIo> {
+    ports: Mutex<P>,
+    timeout: Duration,
+}
+
+impl<P: PortIo> PortIoEc<P> {
+    pub fn new(ports: P) -> PortIoEc<P> {
+        PortIoEc::with_timeout(ports, DEFAULT_TIMEOUT)
+    }
+
+    pub fn with_timeout(ports: P, timeout: Duration) -> PortIoEc<P> {
+        PortIoEc {
+            ports: Mutex::new(ports),
+            timeout,
+        }
+    }
+
+    fn wait_for_status(
+        &self,
+        ports: &mut P,
+        mask: u8,
+        expected: u8,
+        waiting_for: &str,
+    ) -> io::Result<()> {
+        let started = Instant::now();
+        loop {
+            if ports.inb(EC_COMMAND_PORT)? & mask == expected {
+                return Ok(());
+            }
+            if started.elapsed() > self.timeout {
+                return Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    format!("timed out waiting for EC {}", waiting_for),
+                ));
+            }
+            thread::sleep(POLL_INTERVAL);
+        }
+    }
+
+    fn wait_input_buffer_empty(&self, ports: &mut P) -> io::Result<()> {
+        self.wait_for_status(ports, EC_STATUS_IBF, 0, "input buffer to empty")
+    }
+
+    fn wait_output_buffer_full(&self, ports: &mut P) -> io::Result<()> {
+        self.wait_for_status(ports, EC_STATUS_OBF, EC_STATUS_OBF, "output buffer to fill")
+    }
+
+    fn discard_stale_output(&self, ports: &mut P) -> io::Result<()> {
+        if ports.inb(EC_COMMAND_PORT)? & EC_STATUS_OBF != 0 {
+            ports.inb(EC_DATA_PORT)?;
+        }
+        Ok(())
+    }
+
+    fn read_locked(&self, ports: &mut P, register_offset: u8) -> io::Result<u8> {
+        self.discard_stale_output(ports)?;
+        self.wait_input_buffer_empty(ports)?;
+        ports.outb(EC_COMMAND_PORT, EC_COMMAND_READ)?;
+        self.wait_input_buffer_empty(ports)?;
+        ports.outb(EC_DATA_PORT, register_offset)?;
+        self.wait_output_buffer_full(ports)?;
+        ports.inb(EC_DATA_PORT)
+    }
+
+    fn write_locked(&self, ports: &mut P, register_offset: u8, value: u8) -> io::Result<()> {
+        self.wait_input_buffer_empty(ports)?;
+        ports.outb(EC_COMMAND_PORT, EC_COMMAND_WRITE)?;
+        self.wait_input_buffer_empty(ports)?;
+        ports.outb(EC_DATA_PORT, register_offset)?;
+        self.wait_input_buffer_empty(ports)?;
+        ports.outb(EC_DATA_PORT, value)?;
+        self.wait_input_buffer_empty(ports)
+    }
+}
+
+impl<P: PortIo> EcBackend for PortIoEc<P> {
+    fn read_register(&self, register_offset: u64) -> io::Result<u8> {
+        let mut buf = [0u8; 1];
+        self.read_registers(register_offset, &mut buf)?;
+        Ok(buf[0])
+    }
+
+    fn write_register(&self, register_offset: u64, value: u8) -> io::Result<()> {
+        self.apply(EcTransaction::new().write(register_offset, value))
+    }
+
+    fn read_registers(&self, first_register_offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        check_register_range(first_register_offset, buf.len())?;
+        let mut ports = self.ports.lock().unwrap();
+        for (register_offset, value) in (first_register_offset..).zip(buf.iter_mut()) {
+            *value = self.read_locked(&mut ports, register_offset as u8)?;
+        }
+        Ok(())
+    }
+
+    fn apply(&self, transaction: &EcTransaction) -> io::Result<()> {
+        transaction.check()?;
+        let mut ports = self.ports.lock().unwrap();
+        for (i, &(register_offset, value)) in transaction.writes().iter().enumerate() {
+            self.write_locked(&mut ports, register_offset as u8, value)
+                .map_err(|e| transaction.failed_at(i, e))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Ports whose input buffer never empties, like an EC that has stopped responding.
+    struct StuckPorts;
+
+    impl PortIo for StuckPorts {
+        fn inb(&mut self, _port: u64) -> io::Result<u8> {
+            Ok(EC_STATUS_IBF)
+        }
+
+        fn outb(&mut self, _port: u64, _value: u8) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn reads_registers_through_the_handshake() {
+        let mut registers = [0u8; 256];
+        registers[0xb2] = 0x12;
+        registers[0xb3] = 0x34;
+        let ec = PortIoEc::new(SimulatedEcPorts::new(MemoryEc::with_registers(registers)));
+        assert_eq!(ec.read_register(0xb2).unwrap(), 0x12);
+        let mut buf = [0u8; 2];
+        ec.read_registers(0xb2, &mut buf).unwrap();
+        assert_eq!(buf, [0x12, 0x34]);
+    }
+
+    #[test]
+    fn writes_registers_through_the_handshake() {
+        let ec = PortIoEc::new(SimulatedEcPorts::new(MemoryEc::new()));
+        ec.write_register(0xf4, 0x47).unwrap();
+        ec.apply(EcTransaction::new().write(0x89, 0x04).write(0x8a, 0x05))
+            .unwrap();
+        assert_eq!(ec.read_register(0xf4).unwrap(), 0x47);
+        assert_eq!(ec.read_register(0x89).unwrap(), 0x04);
+        assert_eq!(ec.read_register(0x8a).unwrap(), 0x05);
+    }
+
+    #[test]
+    fn times_out_when_the_input_buffer_never_empties() {
+        let ec = PortIoEc::with_timeout(StuckPorts, Duration::from_millis(5));
+        let e = ec.read_register(0x58).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::TimedOut);
+        assert!(e.to_string().contains("input buffer"));
+        let e = ec.write_register(0x58, 1).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::TimedOut);
+    }
+}
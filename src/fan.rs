@@ -0,0 +1,99 @@
+use std::io;
+
+use serde::{Deserialize, Serialize};
+
+use crate::ec::field::RegisterPair;
+use crate::ec::EcBackend;
+
+/// The dividend the firmware's `FRSP` method uses to turn the raw tachometer period into RPM.
+const FRSP_DIVIDEND: u32 = 0x75300;
+
+/// Converts a raw `RPM1`/`RPM2` value the way `FRSP` does; a stopped fan reads as zero.
+pub fn rpm_from_raw(raw: u16) -> u32 {
+    match raw {
+        0 => 0,
+        raw => FRSP_DIVIDEND / raw as u32,
+    }
+}
+
+pub fn read_fan_rpm(ec: &dyn EcBackend, rpm_registers: &RegisterPair) -> io::Result<u32> {
+    rpm_registers.read(ec).map(rpm_from_raw)
+}
+
+/// A fan speed command byte and the duty, in percent, it runs the fan at.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CalibrationPoint {
+    pub duty: f64,
+    pub command: u8,
+    /// The speed `calibrate` measured for the command.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rpm: Option<u32>,
+}
+
+/// The command for `duty`, interpolated linearly between the neighbouring points of a
+/// calibration table sorted by duty, and clamped to its ends. Tables may run in either
+/// direction, as some fans take lower commands as faster.
+pub fn command_for_duty(calibration: &[CalibrationPoint], duty: f64) -> u8 {
+    let (first, last) = (calibration[0], calibration[calibration.len() - 1]);
+    if duty <= first.duty {
+        return first.command;
+    }
+    if duty >= last.duty {
+        return last.command;
+    }
+    let i = calibration.iter().position(|p| p.duty > duty).unwrap();
+    let (below, above) = (calibration[i - 1], calibration[i]);
+    let fraction = (duty - below.duty) / (above.duty - below.duty);
+    let command = below.command as f64 + (above.command as f64 - below.command as f64) * fraction;
+    command.round() as u8
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn table(points: &[(f64, u8)]) -> Vec<CalibrationPoint> {
+        points
+            .iter()
+            .map(|&(duty, command)| CalibrationPoint {
+                duty,
+                command,
+                rpm: None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn interpolates_between_points() {
+        let calibration = table(&[(0.0, 0x09), (25.0, 0x0a), (50.0, 0x3d), (100.0, 0x47)]);
+        assert_eq!(command_for_duty(&calibration, 25.0), 0x0a);
+        assert_eq!(command_for_duty(&calibration, 37.5), 0x24);
+        assert_eq!(command_for_duty(&calibration, 75.0), 0x42);
+        assert_eq!(command_for_duty(&calibration, 12.4), 0x09);
+        assert_eq!(command_for_duty(&calibration, 12.6), 0x0a);
+    }
+
+    #[test]
+    fn interpolates_tables_that_run_downwards() {
+        let calibration = table(&[(0.0, 0xff), (100.0, 0x00)]);
+        assert_eq!(command_for_duty(&calibration, 25.0), 0xbf);
+        assert_eq!(command_for_duty(&calibration, 50.0), 0x80);
+    }
+
+    #[test]
+    fn clamps_to_the_ends_of_the_table() {
+        let calibration = table(&[(20.0, 0x38), (80.0, 0x58)]);
+        assert_eq!(command_for_duty(&calibration, -10.0), 0x38);
+        assert_eq!(command_for_duty(&calibration, 0.0), 0x38);
+        assert_eq!(command_for_duty(&calibration, 100.0), 0x58);
+        assert_eq!(command_for_duty(&calibration, 250.0), 0x58);
+    }
+
+    #[test]
+    fn converts_raw_speeds_like_frsp() {
+        assert_eq!(rpm_from_raw(0), 0);
+        assert_eq!(rpm_from_raw(0x0ecd), 126);
+        assert_eq!(rpm_from_raw(300), 1600);
+    }
+}
@@ -1,59 +1,46 @@
+use std::collections::HashMap;
+use std::convert::TryFrom;
 use std::fmt;
-use std::fs::File;
-use std::io;
-use std::io::prelude::*;
-use std::os::unix::prelude::FileExt;
-use std::process::Command;
+use std::io::{self, Write};
 use std::sync::atomic::{AtomicBool, Ordering};
-use tokio::time::{sleep, Duration};
+use tokio::time::{sleep, Duration, Instant};
 extern crate derive_more;
 use circular_queue::{CircularQueue, Iter};
 use derive_more::Display;
 
-const EMBEDDED_CONTROL_SYS_FILE: &str = "/sys/kernel/debug/ec/ec0/io";
-const POLLING_INTERVAL: u64 = 5000;
-
-const GPU_CONTROL_REGISTER: u64 = 0x89;
-const GPU_TEMPERATURE_REGISTER: u64 = 0xb7;
-const GPU_ACQUIRE_CONTROL: u8 = 0x04;
-const GPU_RELEASE_CONTROL: u8 = 0x12;
-const GPU_SPEED_CONTROL_REGISTER: u64 = 0xb7;
-
-const CPU_CONTROL_REGISTER: u64 = 0xf4;
-const CPU_TEMPERATURE_REGISTER: u64 = 0x58;
-const CPU_ACQUIRE_CONTROL: u8 = 0x02;
-const CPU_RELEASE_CONTROL: u8 = 0x00;
-const CPU_SPEED_CONTROL_REGISTER: u64 = 0xf4;
+mod calibrate;
+mod cli;
+mod discover;
+mod dmi;
+mod dsdt;
+mod dump;
+mod ec;
+mod fan;
+mod nbfc;
+mod nvidia_smi;
+mod profile;
+mod regmap;
+mod repl;
+mod sensor;
+mod snapshot;
+mod watch;
+use calibrate::{Measurement, Settling, Sweep};
+use cli::Args;
+use discover::{Findings, Recording, Sample};
+use dsdt::FieldMapFormat;
+use dump::DumpFormat;
+use ec::{EcBackend, EcWriteError, WriteVerification};
+use nvidia_smi::SharedNvidiaSmi;
+use profile::{FanProfile, Profile, ProfileError, SelectedProfile, SensorProfile, ZoneProfile};
+use regmap::RegisterMap;
+use sensor::{SensorContext, SensorError, Sources, TemperatureSource};
+use watch::{RegisterFilter, Timeline, WatchFormat};
 
 static SHOULD_EXIT: AtomicBool = AtomicBool::new(false);
 
-#[derive(Display, Debug, PartialEq)]
-struct TemperatureParseError;
-
-impl std::error::Error for TemperatureParseError {}
-
 #[derive(Display, Clone)]
 #[display(fmt = "{}°", _0)]
-struct Temperature(u8);
-
-impl Temperature {
-    fn from_milli_c(s: &str) -> Result<Temperature, TemperatureParseError> {
-        s.parse::<u64>()
-            .map_err(|_| TemperatureParseError)
-            .map(|t| Temperature((t / 1000) as u8))
-    }
-}
-
-use std::str::FromStr;
-impl FromStr for Temperature {
-    type Err = TemperatureParseError;
-
-    fn from_str(s: &str) -> Result<Self, Self::Err> {
-        s.parse::<u8>()
-            .map_err(|_| TemperatureParseError)
-            .map(|t| Temperature(t))
-    }
-}
+struct Temperature(f64);
 
 impl fmt::Debug for Temperature {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
@@ -61,113 +48,69 @@ impl fmt::Debug for Temperature {
     }
 }
 
-use std::convert::TryFrom;
-impl TryFrom<String> for Temperature {
-    type Error = TemperatureParseError;
-    fn try_from(s: String) -> Result<Self, Self::Error> {
-        s.parse()
-    }
-}
-
-struct EcFanSpeedCommands {
-    _0_pc: u8,
-    _25_pc: u8,
-    _50_pc: u8,
-    _75_pc: u8,
-    _100_pc: u8,
-}
-
-const CPU_FAN_SPEED_COMMANDS: EcFanSpeedCommands = EcFanSpeedCommands {
-    _0_pc: 0x9,
-    _25_pc: 0xa,
-    _50_pc: 0x3d,
-    _75_pc: 0x42,
-    _100_pc: 0x47,
-};
-
-const GPU_FAN_SPEED_COMMANDS: EcFanSpeedCommands = EcFanSpeedCommands {
-    _0_pc: 0x38,
-    _25_pc: 0x40,
-    _50_pc: 0x48,
-    _75_pc: 0x50,
-    _100_pc: 0x58,
-};
-
-struct HoldEcFanControl {
+struct HoldEcFanControl<'a> {
+    ec: &'a dyn EcBackend,
     control_register_offset: u64,
     release_control_value: u8,
 }
 
-impl Drop for HoldEcFanControl {
+impl Drop for HoldEcFanControl<'_> {
     fn drop(&mut self) {
-        write_to_ec_register(self.control_register_offset, self.release_control_value).unwrap()
+        // Panicking here could abort while already unwinding, so a failure is only reported.
+        if let Err(e) = write_to_ec_register(
+            self.ec,
+            self.control_register_offset,
+            self.release_control_value,
+        ) {
+            eprintln!(
+                "Cannot write {:#04x} back to register {:#x}: {}",
+                self.release_control_value, self.control_register_offset, e
+            );
+        }
     }
 }
 
-impl HoldEcFanControl {
-    pub fn new(
+impl<'a> HoldEcFanControl<'a> {
+    /// The release value is written back if acquiring fails too, as a write that did not
+    /// verify may still have reached the EC.
+    pub async fn new(
+        ec: &'a dyn EcBackend,
         control_register_offset: u64,
         acquire_control_value: u8,
         release_control_value: u8,
-    ) -> io::Result<HoldEcFanControl> {
-        write_to_ec_register(control_register_offset, acquire_control_value)?;
-        Ok(HoldEcFanControl {
+        verification: &WriteVerification,
+    ) -> Result<HoldEcFanControl<'a>, EcWriteError> {
+        let hold = HoldEcFanControl {
+            ec,
             control_register_offset,
             release_control_value,
-        })
-    }
-}
-
-fn get_temp_token_from_nvidia_smi_out(output: &str) -> Option<&str> {
-    output.strip_suffix('\n')?.split_whitespace().last()
-}
-
-fn parse_temp_from_nvidia_smi_out(output: &str) -> Result<Temperature, TemperatureParseError> {
-    match get_temp_token_from_nvidia_smi_out(output) {
-        Some(s) => Temperature::from_str(s),
-        None => Err(TemperatureParseError),
+        };
+        ec::write_verified(
+            ec,
+            control_register_offset,
+            acquire_control_value,
+            verification,
+        )
+        .await?;
+        Ok(hold)
     }
 }
 
-fn read_i7_cpu_temp_from_file() -> std::io::Result<String> {
-    std::fs::read_to_string("/sys/class/thermal/thermal_zone8/temp")
+fn write_to_ec_register(ec: &dyn EcBackend, register_offset: u64, command: u8) -> io::Result<()> {
+    ec.write_register(register_offset, command)
 }
 
-fn read_i7_cpu_temp() -> Result<Temperature, TemperatureParseError> {
-    let temp_s = read_i7_cpu_temp_from_file().unwrap();
-    let temp = temp_s.strip_suffix('\n').unwrap();
-    Temperature::from_milli_c(temp)
+fn read_from_ec_register(ec: &dyn EcBackend, register_offset: u64) -> io::Result<u8> {
+    ec.read_register(register_offset)
 }
 
-fn read_nvidia_gpu_temp() -> Result<Temperature, TemperatureParseError> {
-    let output = Command::new("nvidia-smi")
-        .args(&["stats", "-d", "temp", "-c", "1"])
-        .output()
-        .unwrap();
-
-    let output = std::str::from_utf8(&output.stdout).unwrap();
-    parse_temp_from_nvidia_smi_out(output)
-}
-
-fn write_to_ec_register(register_offset: u64, command: u8) -> io::Result<()> {
-    let mut f = File::create(EMBEDDED_CONTROL_SYS_FILE)?;
-    f.write_at(&[command], register_offset)?;
-    f.flush()
-}
-
-fn read_from_ec_register(register_offset: u64) -> io::Result<u8> {
-    let mut buf = [0u8; 1];
-    let f = File::open(EMBEDDED_CONTROL_SYS_FILE)?;
-    f.read_exact_at(&mut buf, register_offset)?;
-    Ok(buf[0])
-}
-
-fn set_gpu_fan_speed(speed: u8) -> io::Result<()> {
-    write_to_ec_register(GPU_SPEED_CONTROL_REGISTER, speed)
-}
-
-fn set_cpu_fan_speed(speed: u8) -> io::Result<()> {
-    write_to_ec_register(CPU_SPEED_CONTROL_REGISTER, speed)
+async fn set_fan_speed(
+    ec: &dyn EcBackend,
+    fan: &FanProfile,
+    speed: u8,
+    verification: &WriteVerification,
+) -> Result<(), EcWriteError> {
+    ec::write_verified(ec, fan.speed_register, speed, verification).await
 }
 
 fn pid_controller(
@@ -178,17 +121,17 @@ fn pid_controller(
     integral_gain: f64,
     derivative_gain: f64,
 ) -> f64 {
-    let error_vals: Vec<f64> = temperature_history.map(|x| x.0 as f64 - target).collect();
-    if error_vals.len() < 1 {
+    let error_vals: Vec<f64> = temperature_history.map(|x| x.0 - target).collect();
+    if error_vals.is_empty() {
         return 0.0;
     }
-    let latest_err = error_vals[0].clone();
+    let latest_err = error_vals[0];
     if error_vals.len() < 2 {
         return proportional_gain * latest_err;
     }
-    let previous_err = error_vals[1].clone();
+    let previous_err = error_vals[1];
     let integral = error_vals.into_iter().sum::<f64>();
-    let derivative = (latest_err - previous_err) / polling_interval as f64 as f64;
+    let derivative = (latest_err - previous_err) / polling_interval as f64;
     println!(
         "Proportional coeff: {}, integral coeff: {}, derivative coeff: {}",
         proportional_gain * latest_err,
@@ -198,87 +141,951 @@ fn pid_controller(
     proportional_gain * latest_err + integral_gain * integral + derivative_gain * derivative
 }
 
-fn map_gain_to_gpu_fan_speed(gain: f64) -> u8 {
-    match gain {
-        g if g < 15.0 => 0x38,
-        g if g < 25.0 => 0x40,
-        g if g < 35.0 => 0x48,
-        g if g < 45.0 => 0x50,
-        _ => 0x58,
+fn dump_registers(
+    ec: &dyn EcBackend,
+    register_map: &RegisterMap,
+    args: &Args,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let format = args.parsed("--format")?.unwrap_or(DumpFormat::Table);
+    let registers = ec::read_all_registers(ec)?;
+    print!("{}", dump::render(&registers, format, register_map));
+    Ok(())
+}
+
+fn save_snapshot(ec: &dyn EcBackend, args: &Args) -> Result<(), Box<dyn std::error::Error>> {
+    let path = match args.operands().as_slice() {
+        [path] => *path,
+        _ => return Err("usage: snapshot <file>".into()),
+    };
+    snapshot::save(path, &ec::read_all_registers(ec)?)?;
+    Ok(())
+}
+
+fn diff_snapshots(
+    register_map: &RegisterMap,
+    args: &Args,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let (before, after) = match args.operands().as_slice() {
+        [before] => (
+            snapshot::load(before)?,
+            ec::read_all_registers(args.open_ec()?.as_ref())?,
+        ),
+        [before, after] => (snapshot::load(before)?, snapshot::load(after)?),
+        _ => return Err("usage: diff <before> [<after>]".into()),
+    };
+    let diffs = snapshot::diff(&before, &after);
+    print!("{}", snapshot::render_diff(&diffs, register_map));
+    Ok(())
+}
+
+fn show_ec_fields(args: &Args) -> Result<(), Box<dyn std::error::Error>> {
+    let path = match args.operands().as_slice() {
+        [] => dsdt::DSDT_FILE,
+        [path] => *path,
+        _ => return Err("usage: dsdt [<table.aml or source.dsl>]".into()),
+    };
+    let format = args.parsed("--format")?.unwrap_or(FieldMapFormat::Table);
+    print!("{}", dsdt::render(&dsdt::load(path)?, format));
+    Ok(())
+}
+
+fn list_profiles(args: &Args) -> Result<(), Box<dyn std::error::Error>> {
+    let dmi = args.dmi()?;
+    println!("This machine: {}", dmi);
+    for (source, profile) in profile::available_profiles(args.profile_dir())? {
+        let status = if profile.matches.is_empty() {
+            "no match rules"
+        } else if profile.matches(&dmi) {
+            "matches"
+        } else {
+            "does not match"
+        };
+        println!("{:<20}  {:<14}  {}", profile.name, status, source);
     }
+    Ok(())
 }
 
-fn map_gain_to_cpu_fan_speed(gain: f64) -> u8 {
-    match gain {
-        g if g < 10.0 => 0x30,
-        g if g < 20.0 => 0x38,
-        g if g < 30.0 => 0x40,
-        g if g < 40.0 => 0x48,
-        g if g < 50.0 => 0x50,
-        g if g < 60.0 => 0x58,
-        _ => 0x60,
+/// Shows every thermal zone and hwmon channel with its reading, then what each of the
+/// profile's sensors reads.
+async fn list_sensors(
+    selected: &SelectedProfile,
+    register_map: &RegisterMap,
+    args: &Args,
+) -> Result<(), Box<dyn std::error::Error>> {
+    println!("Thermal zones in {}:", args.thermal_dir());
+    let zones = sensor::thermal_zones(args.thermal_dir())?;
+    if zones.is_empty() {
+        println!("  none");
+    }
+    for zone in zones {
+        let reading = sensor::describe_reading(&sensor::ThermalZone::from_info(&zone)).await;
+        println!("  {:<16}  {:<16}  {}", zone.name, zone.zone_type, reading);
+    }
+
+    println!("Hwmon channels in {}:", args.hwmon_dir());
+    let channels = sensor::hwmon_channels(args.hwmon_dir())?;
+    if channels.is_empty() {
+        println!("  none");
     }
+    for channel in channels {
+        let reading = sensor::describe_reading(&sensor::Hwmon::channel(&channel)).await;
+        println!(
+            "  {:<16}  {:<16}  {:<16}  {}",
+            channel.device, channel.driver, channel.label, reading
+        );
+    }
+
+    let nvidia_smi = SharedNvidiaSmi::new(args.nvidia_smi());
+    println!("GPUs from {}:", args.nvidia_smi());
+    match nvidia_smi.get().readings().await {
+        Ok(readings) if readings.is_empty() => println!("  none"),
+        Ok(readings) => {
+            let or_na = |value: Option<f64>, unit: &str| {
+                value.map_or("n/a".to_string(), |v| format!("{:.0}{}", v, unit))
+            };
+            for (gpu, reading) in readings {
+                println!(
+                    "  GPU {:<12}  {:<16}  {:<16}  {:.1}°C",
+                    gpu,
+                    or_na(reading.power_draw, " W"),
+                    or_na(reading.fan_speed, "% fan"),
+                    reading.temperature
+                );
+            }
+        }
+        Err(e) => println!("  error: {}", e),
+    }
+
+    let profile = &selected.profile;
+    let needs_ec = profile
+        .zones
+        .iter()
+        .flat_map(|zone| zone.sensors.iter().chain(&zone.fallback))
+        .any(|sensor| matches!(sensor, SensorProfile::EcRegister(_)));
+    let ec = if needs_ec {
+        Some(args.open_ec()?)
+    } else {
+        None
+    };
+    let context = SensorContext {
+        ec: ec.as_deref(),
+        register_map,
+        thermal_dir: args.thermal_dir(),
+        hwmon_dir: args.hwmon_dir(),
+        nvidia_smi: &nvidia_smi,
+    };
+    println!("Profile '{}' ({}):", profile.name, selected.source);
+    for zone in &profile.zones {
+        let fallback = zone.fallback.iter().map(|sensor| (sensor, " (fallback)"));
+        for (sensor, role) in zone
+            .sensors
+            .iter()
+            .map(|sensor| (sensor, ""))
+            .chain(fallback)
+        {
+            let (name, reading) = match sensor::open(sensor, &context) {
+                Ok(source) => (
+                    source.name().to_string(),
+                    sensor::describe_reading(source.as_ref()).await,
+                ),
+                Err(e) => (sensor.to_string(), format!("error: {}", e)),
+            };
+            println!("  {:<8}  {:<34}  {}", zone.name, name + role, reading);
+        }
+    }
+    Ok(())
 }
 
-#[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error>> {
+fn import_nbfc_config(args: &Args) -> Result<(), Box<dyn std::error::Error>> {
+    let path = match args.operands().as_slice() {
+        [path] => *path,
+        _ => return Err("usage: import-nbfc <config.xml> [--output <profile.toml>]".into()),
+    };
+    let (profile, notes) = nbfc::NbfcConfig::load(path)?.to_profile()?;
+    // The notes go into the profile as comments, and are repeated when it is not printed.
+    let mut header = format!("# Imported from the NBFC config {}.\n", path);
+    for note in &notes {
+        header.push_str(&format!("# Note: {}\n", note));
+    }
+    write_profile(args, &header, &profile)?;
+    if args.value("--output").is_some() {
+        for note in &notes {
+            eprintln!("note: {}", note);
+        }
+    }
+    Ok(())
+}
+
+/// Writes `profile` after the `header` comments to `--output`, or prints it.
+fn write_profile(
+    args: &Args,
+    header: &str,
+    profile: &Profile,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let out = format!("{}\n{}", header, toml::to_string(profile)?);
+    match args.value("--output") {
+        Some(output) => std::fs::write(output, out)?,
+        None => print!("{}", out),
+    }
+    Ok(())
+}
+
+/// Sets `fan` to `command` and waits for its RPM to settle.
+async fn measure_rpm(
+    ec: &dyn EcBackend,
+    fan: &FanProfile,
+    command: u8,
+    settling: &Settling,
+    verification: &WriteVerification,
+) -> Result<Measurement, Box<dyn std::error::Error>> {
+    let rpm_registers = fan.rpm_registers.as_ref().unwrap();
+    set_fan_speed(ec, fan, command, verification).await?;
+    let started = Instant::now();
+    let mut rpms = Vec::new();
+    loop {
+        sleep(settling.interval).await;
+        if SHOULD_EXIT.load(Ordering::Relaxed) {
+            return Err("calibration interrupted".into());
+        }
+        rpms.push(fan::read_fan_rpm(ec, rpm_registers)?);
+        let settled = settling.is_settled(&rpms);
+        if settled || started.elapsed() >= settling.timeout {
+            return Ok(Measurement {
+                command,
+                rpm: *rpms.last().unwrap(),
+                settled,
+            });
+        }
+    }
+}
+
+/// Sweeps each fan's speed register, records the RPM each command settles at, and writes out
+/// the profile with calibration tables built from the measurements. Progress goes to stderr
+/// so that the printed profile can be redirected.
+async fn calibrate_fans(
+    ec: &dyn EcBackend,
+    selected: &SelectedProfile,
+    args: &Args,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let verification = cli::write_verification_from_args(args)?;
+    selected.check_machine(args.flag("--force"))?;
+    let mut settling = Settling::default();
+    if let Some(interval) = args.parsed("--settle-interval-ms")? {
+        settling.interval = Duration::from_millis(interval);
+    }
+    if let Some(timeout) = args.parsed("--settle-timeout-ms")? {
+        settling.timeout = Duration::from_millis(timeout);
+    }
+    let mut profile = selected.profile.clone();
+    let fan_names: Vec<String> = match args.value("--fan") {
+        Some(name) => {
+            let fan = profile
+                .fan(name)
+                .ok_or_else(|| format!("profile '{}' has no fan '{}'", profile.name, name))?;
+            if fan.rpm_registers.is_none() {
+                return Err(format!("fan '{}' has no rpm_registers to measure", name).into());
+            }
+            vec![name.to_string()]
+        }
+        None => profile
+            .fans
+            .iter()
+            .filter(|fan| fan.rpm_registers.is_some())
+            .map(|fan| fan.name.clone())
+            .collect(),
+    };
+    if fan_names.is_empty() {
+        return Err(format!("profile '{}' has no fans with rpm_registers", profile.name).into());
+    }
+    eprintln!(
+        "Calibrating profile '{}' ({})",
+        profile.name, selected.source
+    );
+
+    stop_on_ctrl_c()?;
+    let mut header = format!(
+        "# Calibrated from profile '{}' ({}).\n",
+        profile.name, selected.source
+    );
+    for fan in profile
+        .fans
+        .iter_mut()
+        .filter(|f| fan_names.contains(&f.name))
+    {
+        // Without a range given, sweep the one the current table covers, slowest first.
+        let sweep = Sweep {
+            from: args.byte("--from")?.unwrap_or(fan.calibration[0].command),
+            to: args
+                .byte("--to")?
+                .unwrap_or(fan.calibration.last().unwrap().command),
+            step: args.byte("--step")?.unwrap_or(1),
+        };
+        let mut measurements = Vec::new();
+        {
+            let _hold_fan_control = HoldEcFanControl::new(
+                ec,
+                fan.control_register,
+                fan.acquire_control,
+                fan.release_control,
+                &verification,
+            )
+            .await?;
+            for command in sweep.commands() {
+                let measurement = measure_rpm(ec, fan, command, &settling, &verification).await?;
+                eprintln!(
+                    "{} {:#04x}: {} RPM{}",
+                    fan.name.to_uppercase(),
+                    command,
+                    measurement.rpm,
+                    if measurement.settled {
+                        ""
+                    } else {
+                        " (did not settle)"
+                    }
+                );
+                measurements.push(measurement);
+            }
+        }
+        let calibration =
+            calibrate::analyse(&measurements).map_err(|e| format!("fan '{}': {}", fan.name, e))?;
+        let summary = format!(
+            "{}: spins up at {:#04x} ({} RPM), saturates at {:#04x} ({} RPM)",
+            fan.name,
+            calibration.spin_up.command,
+            calibration.spin_up.rpm,
+            calibration.saturation.command,
+            calibration.saturation.rpm
+        );
+        eprintln!("{}", summary);
+        header.push_str(&format!("# {}\n", summary));
+        let unsettled = measurements.iter().filter(|m| !m.settled).count();
+        if unsettled > 0 {
+            header.push_str(&format!(
+                "# {}: {} commands did not settle within the timeout\n",
+                fan.name, unsettled
+            ));
+        }
+        fan.calibration = calibration.points;
+    }
+    profile.validate()?;
+    write_profile(args, &header, &profile)
+}
+
+/// Asks on stderr, so that a profile printed to stdout can be redirected, and returns the
+/// trimmed answer; end of input answers with nothing.
+fn prompt(question: &str) -> io::Result<String> {
+    eprint!("{}", question);
+    io::stderr().flush()?;
+    let mut answer = String::new();
+    io::stdin().read_line(&mut answer)?;
+    Ok(answer.trim().to_string())
+}
+
+/// Records the EC while the user loads the machine and switches fan modes, ranks the
+/// registers that look like temperatures, fan speeds and fan control, offers to try the
+/// control candidates, and writes out a draft profile. Nothing is written to the EC except
+/// test writes the user confirms, and each of those is undone afterwards.
+async fn discover_registers(
+    ec: &dyn EcBackend,
+    register_map: &RegisterMap,
+    args: &Args,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let verification = cli::write_verification_from_args(args)?;
+    let interval = Duration::from_millis(args.parsed("--interval-ms")?.unwrap_or(1000));
+    let phase_length = Duration::from_secs(args.parsed("--phase-seconds")?.unwrap_or(60));
+    let test_length = Duration::from_secs(args.parsed("--test-seconds")?.unwrap_or(10));
+    // Read up front, so that a failure cannot throw away the recording and test writes.
+    let dmi = args.dmi()?;
+    let sensor = match args.value("--temperature") {
+        Some(path) => SensorProfile::ThermalZone(path.to_string()),
+        None => discover::hottest_thermal_zone(args.thermal_dir())
+            .await?
+            .ok_or("no thermal zone to compare the EC with; pass --temperature FILE")?,
+    };
+    let nvidia_smi = SharedNvidiaSmi::new(args.nvidia_smi());
+    let reference = sensor::open(
+        &sensor,
+        &SensorContext {
+            ec: Some(ec),
+            register_map,
+            thermal_dir: args.thermal_dir(),
+            hwmon_dir: args.hwmon_dir(),
+            nvidia_smi: &nvidia_smi,
+        },
+    )?;
+    eprintln!("Reference temperature: {}", reference.name());
+    eprintln!("The EC is only read; test writes are offered at the end and need confirming.");
+
+    let mut recording = Recording::default();
+    for (phase, (name, instructions)) in discover::PHASES.iter().enumerate() {
+        prompt(&format!(
+            "\n{} ({} of {}): {}. Press Enter to record for {} s. ",
+            name,
+            phase + 1,
+            discover::PHASES.len(),
+            instructions,
+            phase_length.as_secs()
+        ))?;
+        let phase_started = Instant::now();
+        while phase_started.elapsed() < phase_length {
+            match reference.read_celsius().await {
+                Ok(temperature) => recording.push(Sample {
+                    phase,
+                    temperature,
+                    registers: ec::read_all_registers(ec)?.to_vec(),
+                }),
+                Err(e) => eprintln!("Skipping a sample, {}: {}", reference.name(), e),
+            }
+            sleep(interval).await;
+        }
+    }
+
+    let temperature = recording.temperature_candidates();
+    let rpm = recording.rpm_candidates();
+    let control = recording.control_candidates();
+    let mut report = String::new();
+    report.push_str("Temperature registers:\n");
+    for c in temperature.iter().take(discover::CANDIDATES_SHOWN) {
+        report.push_str(&format!(
+            "  {:#04x}  score {:.2}  {}\n",
+            c.register_offset, c.score, c.reason
+        ));
+    }
+    report.push_str("RPM register pairs:\n");
+    for c in rpm.iter().take(discover::CANDIDATES_SHOWN) {
+        report.push_str(&format!(
+            "  {:#04x}/{:#04x}  score {:.2}  {}\n",
+            c.registers.low, c.registers.high, c.score, c.reason
+        ));
+    }
+    report.push_str("Control registers:\n");
+    for c in control.iter().take(discover::CANDIDATES_SHOWN) {
+        report.push_str(&format!(
+            "  {:#04x}  score {:.2}  {}\n",
+            c.register_offset, c.score, c.reason
+        ));
+    }
+    eprint!("\n{}", report);
+
+    let mut findings = Findings {
+        temperature: temperature.first().cloned(),
+        rpm: rpm.first().cloned(),
+        control: control.first().cloned(),
+        confirmed_write: None,
+    };
+    stop_on_ctrl_c()?;
+    for candidate in control.iter().take(discover::CANDIDATES_SHOWN) {
+        let register = candidate.register_offset;
+        let answer = prompt(&format!(
+            "\nTest-write {:#04x} (seen {})? Enter a value to write, or nothing to skip: ",
+            register,
+            discover::hex_list(&candidate.values)
+        ))?;
+        if answer.is_empty() {
+            continue;
+        }
+        let value = ec::parse_register_number(&answer)
+            .ok()
+            .and_then(|n| u8::try_from(n).ok())
+            .ok_or_else(|| format!("'{}' is not a byte", answer))?;
+        let original = ec.read_register(register)?;
+        let confirm = prompt(&format!(
+            "Write {:#04x} to {:#04x} for {} s, then write back {:#04x}? Type 'yes' to confirm: ",
+            value,
+            register,
+            test_length.as_secs(),
+            original
+        ))?;
+        if confirm != "yes" {
+            continue;
+        }
+        let read_rpm = || -> io::Result<Option<u32>> {
+            findings
+                .rpm
+                .as_ref()
+                .map(|c| fan::read_fan_rpm(ec, &c.registers))
+                .transpose()
+        };
+        let rpm_before = read_rpm()?;
+        {
+            // Writes the original value back when dropped, even on errors or Ctrl-C, and when
+            // the write itself does not verify.
+            let _test_write =
+                match HoldEcFanControl::new(ec, register, value, original, &verification).await {
+                    Ok(test_write) => test_write,
+                    Err(e) => {
+                        eprintln!(
+                            "The test write failed, {:#04x} was written back: {}",
+                            original, e
+                        );
+                        continue;
+                    }
+                };
+            let test_started = Instant::now();
+            while test_started.elapsed() < test_length && !SHOULD_EXIT.load(Ordering::Relaxed) {
+                sleep(interval).await;
+            }
+            if let (Some(before), Some(after)) = (rpm_before, read_rpm()?) {
+                eprintln!("RPM went from {} to {}", before, after);
+            }
+        }
+        if SHOULD_EXIT.load(Ordering::Relaxed) {
+            return Err("discovery interrupted".into());
+        }
+        if prompt("Did the fan speed change? [y/N] ")?.eq_ignore_ascii_case("y") {
+            findings.control = Some(candidate.clone());
+            findings.confirmed_write = Some((value, original));
+            break;
+        }
+    }
+
+    let profile = discover::draft_profile(&findings, sensor, &dmi)?;
+    let mut header = String::from(
+        "# Draft profile from `ec-fan-control discover`. Every register below is a guess from\n\
+         # correlations; check each one, and run `calibrate` before relying on the profile.\n",
+    );
+    if findings.confirmed_write.is_none() {
+        header.push_str("# The control values were not confirmed by a test write.\n");
+    }
+    header.push_str(
+        "# The speed register is assumed to be the control register, and the calibration table\n\
+         # only writes back the release value. Both are placeholders: find the speed register\n\
+         # with `watch` and `repl`, then run `calibrate --from N --to M` over its commands.\n",
+    );
+    if let Some(rule) = discover::match_rule(&dmi) {
+        header.push_str(
+            "# The profile has no match rule, so it needs --force until it has been checked.\n\
+             # Then add this one to have it picked for this machine:\n# [[match]]\n",
+        );
+        for line in toml::to_string(&rule)?.lines() {
+            header.push_str(&format!("# {}\n", line));
+        }
+    }
+    for line in report.lines() {
+        header.push_str(&format!("# {}\n", line));
+    }
+    write_profile(args, &header, &profile)
+}
+
+fn stop_on_ctrl_c() -> Result<(), ctrlc::Error> {
     ctrlc::set_handler(|| {
         SHOULD_EXIT.store(true, Ordering::Relaxed);
-    })?;
-    let _hold_gpu_fan_control = HoldEcFanControl::new(
-        GPU_CONTROL_REGISTER,
-        GPU_ACQUIRE_CONTROL,
-        GPU_RELEASE_CONTROL,
-    )?;
-    let _hold_cpu_fan_control = HoldEcFanControl::new(
-        CPU_CONTROL_REGISTER,
-        CPU_ACQUIRE_CONTROL,
-        CPU_RELEASE_CONTROL,
+    })
+}
+
+fn explore_ec(
+    ec: &dyn EcBackend,
+    register_map: &RegisterMap,
+) -> Result<(), Box<dyn std::error::Error>> {
+    stop_on_ctrl_c()?;
+    repl::Session::new(ec, register_map, &SHOULD_EXIT).run()?;
+    Ok(())
+}
+
+async fn watch_registers(
+    ec: &dyn EcBackend,
+    register_map: &RegisterMap,
+    args: &Args,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let filter = RegisterFilter::new(
+        args.value("--registers"),
+        args.value("--ignore"),
+        register_map,
     )?;
+    let interval = Duration::from_millis(args.parsed("--interval-ms")?.unwrap_or(500));
+    let samples: Option<u64> = args.parsed("--samples")?;
+    let max_changes = args.parsed("--max-changes")?;
+    let format = args.parsed("--format")?.unwrap_or(WatchFormat::Timeline);
+    stop_on_ctrl_c()?;
 
-    let mut gpu_temperature_history = CircularQueue::<Temperature>::with_capacity(10);
-    let mut cpu_temperature_history = CircularQueue::<Temperature>::with_capacity(10);
-    let mut last_gpu_fan_speed: u8 = 0x0;
-    let mut next_gpu_fan_speed: u8;
-    let mut last_cpu_fan_speed: u8 = 0x0;
-    let mut next_cpu_fan_speed: u8;
-    while !SHOULD_EXIT.load(Ordering::Relaxed) {
-        gpu_temperature_history.push(read_nvidia_gpu_temp().unwrap());
-        cpu_temperature_history.push(read_i7_cpu_temp().unwrap());
-        let gpu_gain = pid_controller(
-            60.0,
-            gpu_temperature_history.iter(),
-            POLLING_INTERVAL,
-            0.5,
-            0.1,
-            POLLING_INTERVAL as f64 * 2.0,
-        );
-        let cpu_gain = pid_controller(
-            60.0,
-            cpu_temperature_history.iter(),
-            POLLING_INTERVAL,
-            1.0,
-            0.1,
-            POLLING_INTERVAL as f64 * 0.5,
+    let started = Instant::now();
+    let mut timeline = Timeline::new(filter, ec::read_all_registers(ec)?);
+    if format == WatchFormat::Csv {
+        println!("{}", watch::CSV_HEADER);
+    }
+    let mut polls = 0;
+    while !SHOULD_EXIT.load(Ordering::Relaxed) && samples.is_none_or(|n| polls < n) {
+        sleep(interval).await;
+        let changes = timeline.record(started.elapsed(), &ec::read_all_registers(ec)?);
+        for change in changes {
+            let name = register_map.label(change.register_offset);
+            match format {
+                WatchFormat::Json => println!("{}", change.to_json(&name)),
+                WatchFormat::Csv => println!("{}", change.to_csv(&name)),
+                WatchFormat::Timeline => {}
+            }
+        }
+        polls += 1;
+    }
+    if format == WatchFormat::Timeline {
+        print!("{}", timeline.render(max_changes, register_map));
+    }
+    Ok(())
+}
+
+/// The hottest reading among `sources`, carrying on without those that cannot be read.
+async fn hottest_reading(zone: &str, sources: &[Box<dyn TemperatureSource + '_>]) -> Option<f64> {
+    let mut hottest: Option<f64> = None;
+    for source in sources {
+        match source.read_celsius().await {
+            Ok(t) => hottest = Some(hottest.map_or(t, |h| h.max(t))),
+            Err(e) => eprintln!("{} sensor {}: {}", zone, source.name(), e),
+        }
+    }
+    hottest
+}
+
+/// The hottest reading of a zone's sensors, or of its fallback sensors when none of those can
+/// be read.
+async fn zone_temperature(
+    zone: &str,
+    sources: &[Box<dyn TemperatureSource + '_>],
+    fallback: &[Box<dyn TemperatureSource + '_>],
+) -> Option<f64> {
+    let hottest = hottest_reading(zone, sources).await;
+    if hottest.is_some() || fallback.is_empty() {
+        return hottest;
+    }
+    eprintln!(
+        "{}: no sensor could be read, trying the fallback sensors",
+        zone
+    );
+    hottest_reading(zone, fallback).await
+}
+
+/// A zone's sensors and fallback sensors that could be opened, reporting the rest. Only a zone
+/// left with nothing to read is an error.
+fn open_zone_sensors<'a>(
+    zone: &ZoneProfile,
+    context: &SensorContext<'a>,
+) -> Result<(Sources<'a>, Sources<'a>), SensorError> {
+    let (sources, failed) = sensor::open_available(&zone.sensors, context);
+    let (fallback, failed_fallback) = sensor::open_available(&zone.fallback, context);
+    for (sensor, e) in failed.iter().chain(&failed_fallback) {
+        eprintln!("{} sensor {}: {}", zone.name, sensor, e);
+    }
+    if sources.is_empty() && fallback.is_empty() {
+        return Err(SensorError::Invalid(format!(
+            "none of the {} zone's sensors could be opened",
+            zone.name
+        )));
+    }
+    Ok((sources, fallback))
+}
+
+async fn run_controller(
+    ec: &dyn EcBackend,
+    selected: &SelectedProfile,
+    register_map: &RegisterMap,
+    args: &Args,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let verification = cli::write_verification_from_args(args)?;
+    selected.check_machine(args.flag("--force"))?;
+    let profile = &selected.profile;
+    let polling_interval = profile.polling_interval_ms;
+    println!("Using profile '{}' ({})", profile.name, selected.source);
+
+    // Sensors are found before taking control, so a missing one leaves the fans alone.
+    let nvidia_smi = SharedNvidiaSmi::new(args.nvidia_smi());
+    let context = SensorContext {
+        ec: Some(ec),
+        register_map,
+        thermal_dir: args.thermal_dir(),
+        hwmon_dir: args.hwmon_dir(),
+        nvidia_smi: &nvidia_smi,
+    };
+    let zone_sources = profile
+        .zones
+        .iter()
+        .map(|zone| open_zone_sensors(zone, &context))
+        .collect::<Result<Vec<_>, SensorError>>()?;
+
+    stop_on_ctrl_c()?;
+    // Should one fan fail to be acquired, dropping the holds releases every fan tried.
+    let mut _hold_fan_control = Vec::new();
+    for fan in &profile.fans {
+        _hold_fan_control.push(
+            HoldEcFanControl::new(
+                ec,
+                fan.control_register,
+                fan.acquire_control,
+                fan.release_control,
+                &verification,
+            )
+            .await?,
         );
+    }
+
+    let mut temperature_histories: Vec<CircularQueue<Temperature>> = profile
+        .zones
+        .iter()
+        .map(|zone| CircularQueue::with_capacity(zone.controller.history))
+        .collect();
+    let mut last_fan_speeds: Vec<Option<u8>> = vec![None; profile.fans.len()];
+    let mut duties: HashMap<&str, f64> = HashMap::new();
+    while !SHOULD_EXIT.load(Ordering::Relaxed) {
+        for ((zone, history), (sources, fallback)) in profile
+            .zones
+            .iter()
+            .zip(&mut temperature_histories)
+            .zip(&zone_sources)
+        {
+            let duty = match zone_temperature(&zone.name, sources, fallback).await {
+                Some(temperature) => {
+                    history.push(Temperature(temperature));
+                    let gain = pid_controller(
+                        zone.controller.target,
+                        history.iter(),
+                        polling_interval,
+                        zone.controller.proportional_gain,
+                        zone.controller.integral_gain,
+                        zone.controller.derivative_gain,
+                    );
+                    println!("{} Gain: {}", zone.name.to_uppercase(), gain);
+                    gain.clamp(0.0, 100.0)
+                }
+                None => {
+                    eprintln!(
+                        "{}: no sensor could be read, running its fans at full speed",
+                        zone.name
+                    );
+                    100.0
+                }
+            };
+            println!("{} Duty: {:.1}%", zone.name.to_uppercase(), duty);
+            println!(
+                "{} Temperature history: {:?}",
+                zone.name.to_uppercase(),
+                history
+            );
+            duties.insert(&zone.name, duty);
+        }
+
+        for (fan, last_fan_speed) in profile.fans.iter().zip(&mut last_fan_speeds) {
+            // A fan shared between zones cools for whichever zone needs it most.
+            let duty = profile
+                .zones_driving(&fan.name)
+                .map(|zone| duties[zone.name.as_str()])
+                .fold(0.0, f64::max);
+            let next_fan_speed = fan.command_for_duty(duty);
+            if *last_fan_speed != Some(next_fan_speed) {
+                set_fan_speed(ec, fan, next_fan_speed, &verification).await?;
+                *last_fan_speed = Some(next_fan_speed);
+            }
+            // The RPM is only reported, so failing to read it is no reason to stop.
+            if let Some(rpm_registers) = &fan.rpm_registers {
+                match fan::read_fan_rpm(ec, rpm_registers) {
+                    Ok(rpm) => println!("{} Fan RPM: {}", fan.name.to_uppercase(), rpm),
+                    Err(e) => eprintln!("{} Fan RPM: {}", fan.name.to_uppercase(), e),
+                }
+            }
+        }
+        sleep(Duration::from_millis(polling_interval)).await;
+    }
+    Ok(())
+}
 
-        next_gpu_fan_speed = map_gain_to_gpu_fan_speed(gpu_gain);
-        if next_gpu_fan_speed != last_gpu_fan_speed {
-            set_gpu_fan_speed(next_gpu_fan_speed)?;
-            last_gpu_fan_speed = next_gpu_fan_speed;
+#[tokio::main]
+async fn main() {
+    if let Err(e) = run(Args::from_env()).await {
+        eprintln!("Error: {}", e);
+        std::process::exit(1);
+    }
+}
+
+async fn run(args: Args) -> Result<(), Box<dyn std::error::Error>> {
+    // Commands that need neither the machine profile nor the EC.
+    match args.command() {
+        Some("dsdt") => return show_ec_fields(&args),
+        Some("profiles") => return list_profiles(&args),
+        Some("import-nbfc") => return import_nbfc_config(&args),
+        _ => {}
+    }
+
+    if let None | Some("run") | Some("calibrate") | Some("sensors") = args.command() {
+        let profile = args.profile()?;
+        let register_map = &args.register_map(profile.profile.register_map())?;
+        if args.command() == Some("sensors") {
+            return list_sensors(&profile, register_map, &args).await;
         }
-        next_cpu_fan_speed = map_gain_to_cpu_fan_speed(cpu_gain);
-        if next_cpu_fan_speed != last_cpu_fan_speed {
-            set_cpu_fan_speed(next_cpu_fan_speed)?;
-            last_cpu_fan_speed = next_cpu_fan_speed;
+        let ec = args.open_ec()?;
+        return match args.command() {
+            Some("calibrate") => calibrate_fans(ec.as_ref(), &profile, &args).await,
+            _ => run_controller(ec.as_ref(), &profile, register_map, &args).await,
+        };
+    }
+
+    // The rest only use the profile for register names, so they carry on without it.
+    let register_map = &args.register_map(profile_register_names(&args)?)?;
+    if args.command() == Some("diff") {
+        return diff_snapshots(register_map, &args);
+    }
+    let ec = args.open_ec()?;
+    let ec = ec.as_ref();
+    match args.command() {
+        Some("discover") => discover_registers(ec, register_map, &args).await,
+        Some("dump") => dump_registers(ec, register_map, &args),
+        Some("snapshot") => save_snapshot(ec, &args),
+        Some("watch") => watch_registers(ec, register_map, &args).await,
+        Some("repl") => explore_ec(ec, register_map),
+        command => Err(format!("unknown command '{}'", command.unwrap_or_default()).into()),
+    }
+}
+
+/// The register names of the machine profile, or of the built-in one when no profile can be
+/// picked, say because the DMI data cannot be read.
+fn profile_register_names(args: &Args) -> Result<RegisterMap, ProfileError> {
+    match args.profile() {
+        Ok(selected) => Ok(selected.profile.register_map()),
+        Err(e) => {
+            eprintln!(
+                "Using the built-in register names, as no profile could be picked: {}",
+                e
+            );
+            Ok(Profile::builtin()?.register_map())
         }
+    }
+}
 
-        println!("GPU Gain: {}", gpu_gain);
-        println!("GPU Temperature history: {:?}", gpu_temperature_history);
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ec::MemoryEc;
+    use sensor::mock::MockSource;
 
-        println!("CPU Gain: {}", cpu_gain);
-        println!("CPU Temperature history: {:?}", cpu_temperature_history);
-        sleep(Duration::from_millis(POLLING_INTERVAL)).await;
+    /// Records every write, and reads back `reads` from every register.
+    struct RecordingEc {
+        writes: std::sync::Mutex<Vec<(u64, u8)>>,
+        reads: u8,
+    }
+
+    impl RecordingEc {
+        fn new(reads: u8) -> RecordingEc {
+            RecordingEc {
+                writes: std::sync::Mutex::new(Vec::new()),
+                reads,
+            }
+        }
+
+        fn writes(&self) -> Vec<(u64, u8)> {
+            self.writes.lock().unwrap().clone()
+        }
+    }
+
+    impl EcBackend for RecordingEc {
+        fn read_register(&self, _register_offset: u64) -> io::Result<u8> {
+            Ok(self.reads)
+        }
+
+        fn write_register(&self, register_offset: u64, value: u8) -> io::Result<()> {
+            self.writes.lock().unwrap().push((register_offset, value));
+            Ok(())
+        }
+    }
+
+    fn verification() -> WriteVerification {
+        WriteVerification {
+            read_back: true,
+            retries: 1,
+            backoff: Duration::from_millis(1),
+        }
+    }
+
+    #[tokio::test]
+    async fn releases_fan_control_when_dropped() {
+        let ec = MemoryEc::new();
+        {
+            let _hold = HoldEcFanControl::new(&ec, 0x89, 0x04, 0x12, &verification())
+                .await
+                .unwrap();
+            assert_eq!(ec.read_register(0x89).unwrap(), 0x04);
+        }
+        assert_eq!(ec.read_register(0x89).unwrap(), 0x12);
+    }
+
+    #[tokio::test]
+    async fn releases_fan_control_when_acquiring_fails() {
+        let ec = RecordingEc::new(0xff);
+        let result = HoldEcFanControl::new(&ec, 0x89, 0x04, 0x12, &verification()).await;
+        assert!(matches!(result, Err(EcWriteError::Mismatch { .. })));
+        assert_eq!(ec.writes(), [(0x89, 0x04), (0x89, 0x04), (0x89, 0x12)]);
+    }
+
+    fn sources(sources: &[&MockSource]) -> Vec<Box<dyn TemperatureSource + 'static>> {
+        sources
+            .iter()
+            .map(|&source| Box::new(source.clone()) as Box<dyn TemperatureSource>)
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn takes_the_hottest_readable_sensor() {
+        let (a, b, broken) = (
+            MockSource::new("a", Ok(51.0)),
+            MockSource::new("b", Ok(64.5)),
+            MockSource::new("broken", Err("gone")),
+        );
+        assert_eq!(
+            hottest_reading("cpu", &sources(&[&a, &broken, &b])).await,
+            Some(64.5)
+        );
+        assert_eq!(hottest_reading("cpu", &sources(&[&broken])).await, None);
+        assert_eq!(hottest_reading("cpu", &[]).await, None);
+    }
+
+    #[tokio::test]
+    async fn reads_the_fallback_only_when_no_sensor_can_be_read() {
+        let (primary, fallback) = (
+            MockSource::new("primary", Ok(70.0)),
+            MockSource::new("ec", Ok(55.0)),
+        );
+        let zone = |primary: &MockSource| {
+            let (primary, fallback) = (sources(&[primary]), sources(&[&fallback]));
+            async move { zone_temperature("cpu", &primary, &fallback).await }
+        };
+        assert_eq!(zone(&primary).await, Some(70.0));
+        assert_eq!(fallback.reads(), 0);
+
+        primary.set(Err("unplugged"));
+        assert_eq!(zone(&primary).await, Some(55.0));
+        assert_eq!(fallback.reads(), 1);
+
+        fallback.set(Err("EC busy"));
+        assert_eq!(zone(&primary).await, None);
+
+        primary.set(Ok(72.0));
+        assert_eq!(zone(&primary).await, Some(72.0));
+        assert_eq!(fallback.reads(), 2);
+    }
+
+    #[test]
+    fn starts_unless_a_zone_has_no_sensor_at_all() {
+        let builtin = Profile::builtin().unwrap();
+        let zone = |sensors: Vec<SensorProfile>, fallback: Vec<SensorProfile>| ZoneProfile {
+            sensors,
+            fallback,
+            ..builtin.zones[0].clone()
+        };
+        let missing = || SensorProfile::ThermalZoneType(vec!["x86_pkg_temp".to_string()]);
+        let present = || SensorProfile::ThermalZone("/nonexistent/temp".to_string());
+        let map = RegisterMap::default();
+        let nvidia_smi = SharedNvidiaSmi::new(sensor::NVIDIA_SMI);
+        let context = SensorContext {
+            ec: None,
+            register_map: &map,
+            thermal_dir: "/nonexistent",
+            hwmon_dir: "/nonexistent",
+            nvidia_smi: &nvidia_smi,
+        };
+
+        let (sources, fallback) =
+            open_zone_sensors(&zone(vec![missing(), present()], vec![missing()]), &context)
+                .unwrap();
+        assert_eq!((sources.len(), fallback.len()), (1, 0));
+        let (sources, fallback) =
+            open_zone_sensors(&zone(vec![missing()], vec![present()]), &context).unwrap();
+        assert_eq!((sources.len(), fallback.len()), (0, 1));
+        assert!(open_zone_sensors(&zone(vec![missing()], vec![missing()]), &context).is_err());
+        assert!(open_zone_sensors(&zone(vec![missing()], Vec::new()), &context).is_err());
     }
-    Ok(())
 }
@@ -0,0 +1,615 @@
+use std::convert::TryFrom;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use derive_more::Display;
+use roxmltree::{Document, Node};
+
+use crate::fan::CalibrationPoint;
+use crate::profile::{FanProfile, MatchRule, Profile, ZoneProfile};
+
+/// The fields of a NoteBook FanControl `FanControlConfigV2` file that map onto a profile.
+#[derive(Clone, Debug, PartialEq)]
+pub struct NbfcConfig {
+    pub notebook_model: String,
+    pub ec_poll_interval_ms: u64,
+    pub read_write_words: bool,
+    pub fans: Vec<NbfcFan>,
+    pub register_writes: Vec<NbfcRegisterWrite>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct NbfcFan {
+    pub display_name: String,
+    pub read_register: u64,
+    pub write_register: u64,
+    pub min_speed_value: u64,
+    pub max_speed_value: u64,
+    pub reset_required: bool,
+    pub fan_speed_reset_value: u64,
+    /// `(up_threshold, down_threshold, percent)` rows of NBFC's own fan curve.
+    pub temperature_thresholds: Vec<(u64, u64, f64)>,
+    /// `(percent, value)` pairs that replace the linear min/max scaling when writing.
+    pub speed_overrides: Vec<(f64, u64)>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct NbfcRegisterWrite {
+    pub write_mode: String,
+    pub write_occasion: String,
+    pub register: u64,
+    pub value: u64,
+    pub reset_required: bool,
+    pub reset_value: u64,
+    pub reset_write_mode: String,
+    pub description: String,
+}
+
+#[derive(Display, Debug)]
+pub enum NbfcError {
+    #[display(fmt = "{}", _0)]
+    Io(io::Error),
+    #[display(fmt = "{}", _0)]
+    Xml(roxmltree::Error),
+    #[display(fmt = "{}", _0)]
+    Invalid(String),
+    #[display(fmt = "cannot convert: {}", _0)]
+    Unsupported(String),
+}
+
+impl std::error::Error for NbfcError {}
+
+impl From<io::Error> for NbfcError {
+    fn from(e: io::Error) -> NbfcError {
+        NbfcError::Io(e)
+    }
+}
+
+impl NbfcConfig {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<NbfcConfig, NbfcError> {
+        NbfcConfig::parse(&fs::read_to_string(path)?)
+    }
+
+    pub fn parse(xml: &str) -> Result<NbfcConfig, NbfcError> {
+        let document = Document::parse(xml).map_err(NbfcError::Xml)?;
+        let root = document.root_element();
+        if root.tag_name().name() != "FanControlConfigV2" {
+            return Err(NbfcError::Invalid(format!(
+                "expected a FanControlConfigV2 document, found {}",
+                root.tag_name().name()
+            )));
+        }
+        Ok(NbfcConfig {
+            notebook_model: text(root, "NotebookModel").unwrap_or("").to_string(),
+            ec_poll_interval_ms: number(root, "EcPollInterval")?.unwrap_or(3000),
+            read_write_words: boolean(root, "ReadWriteWords")?,
+            fans: children(root, "FanConfigurations", "FanConfiguration")
+                .map(parse_fan)
+                .collect::<Result<_, _>>()?,
+            register_writes: children(
+                root,
+                "RegisterWriteConfigurations",
+                "RegisterWriteConfiguration",
+            )
+            .map(parse_register_write)
+            .collect::<Result<_, _>>()?,
+        })
+    }
+
+    /// Converts the config into a profile. NBFC has no notion of which fan cools what, so
+    /// fans whose names mention the GPU go into a `gpu` zone and the rest into a `cpu` zone,
+    /// with sensors and PID gains from the default profile's zones of the same names.
+    /// Anything that could not be carried over is described in the returned notes.
+    pub fn to_profile(&self) -> Result<(Profile, Vec<String>), NbfcError> {
+        let mut notes = Vec::new();
+        if self.read_write_words {
+            return Err(NbfcError::Unsupported(
+                "ReadWriteWords is set, but fan speeds are single registers here".to_string(),
+            ));
+        }
+        if self.fans.is_empty() {
+            return Err(NbfcError::Invalid("the config has no fans".to_string()));
+        }
+        let defaults = Profile::builtin().map_err(|e| NbfcError::Invalid(e.to_string()))?;
+
+        // Only writes made once on start-up and undone by a plain write on exit fit the
+        // acquire and release values of a control register.
+        let (init_writes, other_writes): (Vec<&NbfcRegisterWrite>, Vec<_>) =
+            self.register_writes.iter().partition(|w| {
+                w.write_occasion == "OnInitialization"
+                    && w.write_mode == "Set"
+                    && (!w.reset_required || w.reset_write_mode == "Set")
+            });
+        for write in other_writes {
+            notes.push(format!(
+                "{} {} write of {:#04x} to {:#04x} ({}) is not carried over",
+                write.write_occasion,
+                write.write_mode,
+                write.value,
+                write.register,
+                write.description
+            ));
+        }
+
+        // NBFC makes these writes for the machine rather than for a fan, so each fan takes the
+        // one that looks like its own, and a write no fan claims goes to the first fan left
+        // without one.
+        let mut unclaimed = init_writes;
+        let mut controls: Vec<Option<&NbfcRegisterWrite>> = self
+            .fans
+            .iter()
+            .map(|fan| {
+                let i = unclaimed
+                    .iter()
+                    .position(|write| fan.is_controlled_by(write))?;
+                Some(unclaimed.remove(i))
+            })
+            .collect();
+        for (nbfc_fan, control) in self.fans.iter().zip(&mut controls) {
+            if control.is_none() && !unclaimed.is_empty() {
+                let write = unclaimed.remove(0);
+                if self.fans.len() > 1 {
+                    notes.push(format!(
+                        "initialisation write of {:#04x} to {:#04x} ({}) is made once for every \
+                         fan in NBFC, and is taken as {}'s control register here",
+                        write.value, write.register, write.description, nbfc_fan.display_name
+                    ));
+                }
+                *control = Some(write);
+            }
+        }
+        for write in unclaimed {
+            notes.push(format!(
+                "initialisation write of {:#04x} to {:#04x} ({}) is not carried over, profiles \
+                 hold one control register per fan",
+                write.value, write.register, write.description
+            ));
+        }
+
+        let mut fans: Vec<FanProfile> = Vec::new();
+        let mut zones: Vec<ZoneProfile> = Vec::new();
+        for (i, (nbfc_fan, control)) in self.fans.iter().zip(controls).enumerate() {
+            let mut fan = match convert_fan(nbfc_fan, control, &mut notes)? {
+                Some(fan) => fan,
+                None => continue,
+            };
+            if fans.iter().any(|other| other.name == fan.name) {
+                fan.name = format!("{}_{}", fan.name, i + 1);
+            }
+
+            let zone_name = if is_gpu_fan(nbfc_fan) { "gpu" } else { "cpu" };
+            let zone = match zones.iter_mut().find(|zone| zone.name == zone_name) {
+                Some(zone) => zone,
+                None => {
+                    let template = defaults
+                        .zones
+                        .iter()
+                        .find(|zone| zone.name == zone_name)
+                        .unwrap_or(&defaults.zones[0]);
+                    zones.push(ZoneProfile {
+                        name: zone_name.to_string(),
+                        fans: Vec::new(),
+                        // The template's fallback may name registers of the built-in fans.
+                        fallback: Vec::new(),
+                        ..template.clone()
+                    });
+                    zones.last_mut().unwrap()
+                }
+            };
+            if let Some(start) = nbfc_fan.start_temperature() {
+                // Where NBFC first starts a fan is the nearest thing it has to a target.
+                if zone.fans.is_empty() || start < zone.controller.target {
+                    zone.controller.target = start;
+                }
+            }
+            zone.fans.push(fan.name.clone());
+            fans.push(fan);
+        }
+        if fans.is_empty() {
+            return Err(NbfcError::Unsupported(
+                "no fan has a control write or a reset value to hand it back with".to_string(),
+            ));
+        }
+
+        let mut matches = Vec::new();
+        if !self.notebook_model.is_empty() {
+            matches.push(MatchRule {
+                product_name: Some(self.notebook_model.clone()),
+                ..MatchRule::default()
+            });
+            notes.push(format!(
+                "the match rule assumes the DMI product_name is '{}', check it with \
+                 'ec-fan-control profiles'",
+                self.notebook_model
+            ));
+        }
+        let profile = Profile {
+            name: if self.notebook_model.is_empty() {
+                "nbfc-import".to_string()
+            } else {
+                self.notebook_model.clone()
+            },
+            matches,
+            polling_interval_ms: self.ec_poll_interval_ms,
+            fans,
+            zones,
+            registers: Default::default(),
+        };
+        profile.validate().map_err(NbfcError::Invalid)?;
+        Ok((profile, notes))
+    }
+}
+
+/// The fan as a profile fan, or `None` when there is no safe way to take it over and hand it
+/// back.
+fn convert_fan(
+    fan: &NbfcFan,
+    control: Option<&NbfcRegisterWrite>,
+    notes: &mut Vec<String>,
+) -> Result<Option<FanProfile>, NbfcError> {
+    let byte = |value: u64, what: &str| {
+        u8::try_from(value).map_err(|_| {
+            NbfcError::Invalid(format!(
+                "{} {} of {} is not a byte",
+                what, value, fan.display_name
+            ))
+        })
+    };
+    let (control_register, acquire_control, release_control) = match control {
+        Some(write) => (
+            write.register,
+            byte(write.value, "initialisation value")?,
+            byte(
+                if write.reset_required {
+                    write.reset_value
+                } else {
+                    write.value
+                },
+                "reset value",
+            )?,
+        ),
+        None if fan.reset_required => {
+            // NBFC takes these fans over just by writing their speed, and hands them back
+            // by writing the reset value.
+            let reset = byte(fan.fan_speed_reset_value, "FanSpeedResetValue")?;
+            notes.push(format!(
+                "{} has no initialisation write, so control is taken and released by \
+                 writing its reset value {:#04x} to the speed register",
+                fan.display_name, reset
+            ));
+            (fan.write_register, reset, reset)
+        }
+        None => {
+            // Without ResetRequired, FanSpeedResetValue is only a default, and writing it
+            // could stop the fan.
+            notes.push(format!(
+                "{} is left out, as it has no initialisation write and no reset value to \
+                 take it over and hand it back with",
+                fan.display_name
+            ));
+            return Ok(None);
+        }
+    };
+    if control.is_some() && fan.reset_required {
+        notes.push(format!(
+            "{} also resets its speed register to {:#04x} in NBFC, which is not carried over",
+            fan.display_name, fan.fan_speed_reset_value
+        ));
+    }
+    if fan.read_register != fan.write_register {
+        notes.push(format!(
+            "{} reads its speed back from {:#04x}, which profiles have no place for",
+            fan.display_name, fan.read_register
+        ));
+    }
+
+    let mut duties = vec![0.0, 100.0];
+    duties.extend(fan.speed_overrides.iter().map(|&(percent, _)| percent));
+    duties.retain(|duty| (0.0..=100.0).contains(duty));
+    duties.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    duties.dedup();
+    let calibration = duties
+        .into_iter()
+        .map(|duty| {
+            Ok(CalibrationPoint {
+                duty,
+                command: byte(fan.speed_value(duty), "speed value")?,
+                rpm: None,
+            })
+        })
+        .collect::<Result<_, NbfcError>>()?;
+    Ok(Some(FanProfile {
+        name: fan.profile_name(),
+        control_register,
+        acquire_control,
+        release_control,
+        speed_register: fan.write_register,
+        rpm_registers: None,
+        temperature_register: None,
+        calibration,
+    }))
+}
+
+impl NbfcFan {
+    /// The value NBFC writes for `percent`, from an override or else scaled between the
+    /// minimum and maximum speed values, which may run in either direction.
+    pub fn speed_value(&self, percent: f64) -> u64 {
+        if let Some(&(_, value)) = self.speed_overrides.iter().find(|&&(p, _)| p == percent) {
+            return value;
+        }
+        let (min, max) = (self.min_speed_value as f64, self.max_speed_value as f64);
+        (min + (max - min) * percent / 100.0).round() as u64
+    }
+
+    /// Whether an initialisation write looks like it is meant for this fan: it writes the
+    /// fan's own speed register, or its description names the fan.
+    fn is_controlled_by(&self, write: &NbfcRegisterWrite) -> bool {
+        write.register == self.write_register
+            || write
+                .description
+                .to_lowercase()
+                .contains(&self.display_name.to_lowercase())
+    }
+
+    /// The temperature at which NBFC's thresholds first run the fan.
+    pub fn start_temperature(&self) -> Option<f64> {
+        self.temperature_thresholds
+            .iter()
+            .find(|&&(_, _, percent)| percent > 0.0)
+            .map(|&(up, _, _)| up as f64)
+    }
+
+    /// The display name as a fan name, e.g. `cpu_fan` for `CPU fan`.
+    pub fn profile_name(&self) -> String {
+        let name: String = self
+            .display_name
+            .to_lowercase()
+            .chars()
+            .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+            .collect();
+        match name.trim_matches('_') {
+            "" => "fan".to_string(),
+            name => name.to_string(),
+        }
+    }
+}
+
+fn is_gpu_fan(fan: &NbfcFan) -> bool {
+    fan.display_name.to_lowercase().contains("gpu")
+}
+
+fn parse_fan(node: Node) -> Result<NbfcFan, NbfcError> {
+    Ok(NbfcFan {
+        display_name: text(node, "FanDisplayName").unwrap_or("fan").to_string(),
+        read_register: required_number(node, "ReadRegister")?,
+        write_register: required_number(node, "WriteRegister")?,
+        min_speed_value: required_number(node, "MinSpeedValue")?,
+        max_speed_value: required_number(node, "MaxSpeedValue")?,
+        reset_required: boolean(node, "ResetRequired")?,
+        fan_speed_reset_value: number(node, "FanSpeedResetValue")?.unwrap_or(0),
+        temperature_thresholds: children(node, "TemperatureThresholds", "TemperatureThreshold")
+            .map(|t| {
+                Ok((
+                    required_number(t, "UpThreshold")?,
+                    required_number(t, "DownThreshold")?,
+                    required_float(t, "FanSpeed")?,
+                ))
+            })
+            .collect::<Result<_, NbfcError>>()?,
+        speed_overrides: children(
+            node,
+            "FanSpeedPercentageOverrides",
+            "FanSpeedPercentageOverride",
+        )
+        // Overrides that only apply when reading the speed back do not affect writes.
+        .filter(|o| text(*o, "TargetOperation").unwrap_or("ReadWrite") != "Read")
+        .map(|o| {
+            Ok((
+                required_float(o, "FanSpeedPercentage")?,
+                required_number(o, "FanSpeedValue")?,
+            ))
+        })
+        .collect::<Result<_, NbfcError>>()?,
+    })
+}
+
+fn parse_register_write(node: Node) -> Result<NbfcRegisterWrite, NbfcError> {
+    Ok(NbfcRegisterWrite {
+        write_mode: text(node, "WriteMode").unwrap_or("Set").to_string(),
+        write_occasion: text(node, "WriteOccasion")
+            .unwrap_or("OnInitialization")
+            .to_string(),
+        register: required_number(node, "Register")?,
+        value: required_number(node, "Value")?,
+        reset_required: boolean(node, "ResetRequired")?,
+        reset_value: number(node, "ResetValue")?.unwrap_or(0),
+        reset_write_mode: text(node, "ResetWriteMode").unwrap_or("Set").to_string(),
+        description: text(node, "Description").unwrap_or("").to_string(),
+    })
+}
+
+fn child<'a, 'input>(node: Node<'a, 'input>, name: &str) -> Option<Node<'a, 'input>> {
+    node.children().find(|n| n.has_tag_name(name))
+}
+
+/// The `item` elements inside `node`'s `list` element.
+fn children<'a, 'input: 'a>(
+    node: Node<'a, 'input>,
+    list: &'a str,
+    item: &'a str,
+) -> impl Iterator<Item = Node<'a, 'input>> + 'a {
+    child(node, list)
+        .into_iter()
+        .flat_map(move |list| list.children().filter(move |n| n.has_tag_name(item)))
+}
+
+fn text<'a>(node: Node<'a, '_>, name: &str) -> Option<&'a str> {
+    child(node, name).and_then(|n| n.text()).map(str::trim)
+}
+
+fn number(node: Node, name: &str) -> Result<Option<u64>, NbfcError> {
+    text(node, name)
+        .map(|s| {
+            s.parse()
+                .map_err(|_| NbfcError::Invalid(format!("{} '{}' is not a number", name, s)))
+        })
+        .transpose()
+}
+
+fn required_number(node: Node, name: &str) -> Result<u64, NbfcError> {
+    number(node, name)?.ok_or_else(|| NbfcError::Invalid(format!("missing {}", name)))
+}
+
+fn required_float(node: Node, name: &str) -> Result<f64, NbfcError> {
+    let s = text(node, name).ok_or_else(|| NbfcError::Invalid(format!("missing {}", name)))?;
+    s.parse()
+        .map_err(|_| NbfcError::Invalid(format!("{} '{}' is not a number", name, s)))
+}
+
+fn boolean(node: Node, name: &str) -> Result<bool, NbfcError> {
+    match text(node, name) {
+        None | Some("false") => Ok(false),
+        Some("true") => Ok(true),
+        Some(s) => Err(NbfcError::Invalid(format!(
+            "{} '{}' is not true or false",
+            name, s
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fixture(name: &str) -> String {
+        let path = format!("{}/fixtures/nbfc/{}", env!("CARGO_MANIFEST_DIR"), name);
+        fs::read_to_string(path).unwrap()
+    }
+
+    fn convert(xml: &str) -> (Profile, Vec<String>) {
+        NbfcConfig::parse(xml).unwrap().to_profile().unwrap()
+    }
+
+    fn commands(fan: &FanProfile) -> Vec<(f64, u8)> {
+        fan.calibration
+            .iter()
+            .map(|p| (p.duty, p.command))
+            .collect()
+    }
+
+    fn control(fan: &FanProfile) -> (u64, u8, u8) {
+        (
+            fan.control_register,
+            fan.acquire_control,
+            fan.release_control,
+        )
+    }
+
+    #[test]
+    fn converts_two_fans_into_cpu_and_gpu_zones() {
+        let (profile, notes) = convert(&fixture("two-fans.xml"));
+        assert_eq!(profile.name, "Example Gaming 15");
+        assert_eq!(profile.polling_interval_ms, 3000);
+        assert_eq!(
+            profile.matches[0].product_name.as_deref(),
+            Some("Example Gaming 15")
+        );
+
+        let (cpu, gpu) = (&profile.fans[0], &profile.fans[1]);
+        assert_eq!(
+            (cpu.name.as_str(), gpu.name.as_str()),
+            ("cpu_fan", "gpu_fan")
+        );
+        assert_eq!(control(cpu), (0xf4, 0x02, 0x00));
+        assert_eq!(control(gpu), (0x89, 0x04, 0x12));
+        assert_eq!((cpu.speed_register, gpu.speed_register), (0xf4, 0xb7));
+        // The read-only override at 100% is left out.
+        assert_eq!(
+            commands(cpu),
+            [(0.0, 9), (50.0, 61), (75.0, 66), (100.0, 71)]
+        );
+        assert_eq!(commands(gpu), [(0.0, 56), (100.0, 88)]);
+
+        let zones: Vec<(&str, &[String], f64)> = profile
+            .zones
+            .iter()
+            .map(|z| (z.name.as_str(), z.fans.as_slice(), z.controller.target))
+            .collect();
+        assert_eq!(
+            zones,
+            [
+                ("cpu", &["cpu_fan".to_string()][..], 60.0),
+                ("gpu", &["gpu_fan".to_string()][..], 65.0)
+            ]
+        );
+        assert!(notes.iter().all(|n| !n.contains("not carried over")));
+    }
+
+    #[test]
+    fn gives_a_fan_only_the_initialisation_writes_meant_for_it() {
+        let mut config = NbfcConfig::parse(&fixture("two-fans.xml")).unwrap();
+        // The GPU fan's write goes first, and is still matched to it by its description.
+        config.register_writes.reverse();
+        let (profile, _) = config.to_profile().unwrap();
+        assert_eq!(control(&profile.fans[0]), (0xf4, 0x02, 0x00));
+        assert_eq!(control(&profile.fans[1]), (0x89, 0x04, 0x12));
+
+        // With only the CPU fan's write, the GPU fan does not share it, and without a reset
+        // value of its own there is nothing safe to write to its speed register.
+        config.register_writes.retain(|w| w.register == 0xf4);
+        let (profile, notes) = config.to_profile().unwrap();
+        assert_eq!(profile.fans.len(), 1);
+        assert_eq!(control(&profile.fans[0]), (0xf4, 0x02, 0x00));
+        assert!(profile.zones.iter().all(|z| z.name != "gpu"));
+        assert!(notes.iter().any(|n| n.starts_with("GPU fan is left out")));
+
+        // Given a reset value, it is taken over through its speed register.
+        config.fans[1].reset_required = true;
+        config.fans[1].fan_speed_reset_value = 0x38;
+        let (profile, _) = config.to_profile().unwrap();
+        assert_eq!(control(&profile.fans[1]), (0xb7, 0x38, 0x38));
+
+        // A write for no fan in particular is made once, by the first fan.
+        config.register_writes[0].register = 0x93;
+        config.register_writes[0].description = "Manual mode".to_string();
+        let (profile, notes) = config.to_profile().unwrap();
+        assert_eq!(control(&profile.fans[0]), (0x93, 0x02, 0x00));
+        assert_eq!(control(&profile.fans[1]), (0xb7, 0x38, 0x38));
+        assert!(notes.iter().any(|n| n.contains("made once for every fan")));
+
+        config.fans[1].reset_required = false;
+        config.register_writes.clear();
+        assert!(matches!(
+            config.to_profile(),
+            Err(NbfcError::Unsupported(_))
+        ));
+    }
+
+    #[test]
+    fn converts_a_single_inverted_fan() {
+        let (profile, notes) = convert(&fixture("single-fan-inverted.xml"));
+        assert_eq!(profile.fans.len(), 1);
+        let fan = &profile.fans[0];
+        assert_eq!(fan.name, "system_fan");
+        assert_eq!(control(fan), (47, 0xff, 0xff));
+        assert_eq!(commands(fan), [(0.0, 255), (100.0, 0)]);
+        assert_eq!(fan.command_for_duty(50.0), 128);
+        assert_eq!(profile.zones.len(), 1);
+        assert_eq!(profile.zones[0].controller.target, 55.0);
+        assert!(notes.iter().any(|n| n.contains("OnWriteFanSpeed Or write")));
+        assert!(notes
+            .iter()
+            .any(|n| n.contains("reads its speed back from 0x2e")));
+    }
+
+    #[test]
+    fn rejects_read_write_words() {
+        let config = NbfcConfig::parse(&fixture("read-write-words.xml")).unwrap();
+        assert!(config.read_write_words);
+        assert!(matches!(
+            config.to_profile(),
+            Err(NbfcError::Unsupported(_))
+        ));
+    }
+}
@@ -0,0 +1,398 @@
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+use async_process::{Command, Stdio};
+use futures_lite::io::{AsyncBufReadExt, BufReader};
+use futures_lite::StreamExt;
+use tokio::sync::Notify;
+use tokio::task::JoinHandle;
+
+use crate::sensor::{ReadFuture, SensorError, TemperatureSource, Unit};
+
+#[derive(Clone, Copy, Debug)]
+struct Timing {
+    /// How often `nvidia-smi` is asked to report.
+    query_interval: Duration,
+    /// How long to wait before starting `nvidia-smi` again after it exits or cannot be started.
+    restart_delay: Duration,
+    /// How long a GPU may go without reporting, from the start or from its last reading, before
+    /// reads fail rather than wait.
+    max_age: Duration,
+}
+
+const TIMING: Timing = Timing {
+    query_interval: Duration::from_millis(1000),
+    restart_delay: Duration::from_secs(2),
+    max_age: Duration::from_secs(5),
+};
+
+/// The fields asked for, in the order `parse_line` expects them.
+const QUERY: &str = "--query-gpu=index,temperature.gpu,power.draw,fan.speed";
+
+/// One line of `nvidia-smi` output.
+#[derive(Clone, Debug, PartialEq)]
+pub struct GpuReading {
+    pub temperature: f64,
+    /// In watts, where the GPU reports it.
+    pub power_draw: Option<f64>,
+    /// In percent, where the GPU has a fan of its own.
+    pub fan_speed: Option<f64>,
+    pub at: Instant,
+}
+
+#[derive(Default)]
+struct State {
+    readings: BTreeMap<u32, GpuReading>,
+    /// Why the latest run produced no readings, until one parses.
+    error: Option<String>,
+}
+
+#[derive(Default)]
+struct Shared {
+    state: Mutex<State>,
+    updated: Notify,
+}
+
+impl Shared {
+    fn update(&self, f: impl FnOnce(&mut State)) {
+        f(&mut self.state.lock().unwrap());
+        self.updated.notify_waiters();
+    }
+}
+
+/// A single `nvidia-smi` reporting every GPU at the query interval, started again whenever it
+/// exits, so that reads take the latest reading instead of running it each time.
+pub struct NvidiaSmi {
+    command: String,
+    shared: Arc<Shared>,
+    started: Instant,
+    timing: Timing,
+    task: JoinHandle<()>,
+}
+
+impl NvidiaSmi {
+    /// Starts `command` in the background; it needs a tokio runtime.
+    pub fn start(command: &str) -> NvidiaSmi {
+        NvidiaSmi::start_with(command, TIMING)
+    }
+
+    fn start_with(command: &str, timing: Timing) -> NvidiaSmi {
+        let shared = Arc::new(Shared::default());
+        let task = tokio::spawn(keep_running(command.to_string(), shared.clone(), timing));
+        NvidiaSmi {
+            command: command.to_string(),
+            shared,
+            started: Instant::now(),
+            timing,
+            task,
+        }
+    }
+
+    pub fn command(&self) -> &str {
+        &self.command
+    }
+
+    /// Every GPU's latest reading, waiting for the first ones after a start.
+    pub async fn readings(&self) -> Result<BTreeMap<u32, GpuReading>, SensorError> {
+        self.wait_for(|state| !state.readings.is_empty()).await;
+        let state = self.shared.state.lock().unwrap();
+        match &state.error {
+            Some(error) if state.readings.is_empty() => Err(SensorError::Command(error.clone())),
+            _ => Ok(state.readings.clone()),
+        }
+    }
+
+    /// `gpu`'s temperature, as long as its latest reading is recent.
+    pub async fn temperature(&self, gpu: u32) -> Result<f64, SensorError> {
+        self.wait_for(|state| state.readings.contains_key(&gpu))
+            .await;
+        let state = self.shared.state.lock().unwrap();
+        let error = state
+            .error
+            .as_ref()
+            .map(|e| format!(" ({})", e))
+            .unwrap_or_default();
+        match state.readings.get(&gpu) {
+            Some(reading) if reading.at.elapsed() <= self.timing.max_age => Ok(reading.temperature),
+            Some(reading) => Err(SensorError::Command(format!(
+                "no reading from {} for GPU {} in {:.0}s{}",
+                self.command,
+                gpu,
+                reading.at.elapsed().as_secs_f64(),
+                error
+            ))),
+            None => Err(SensorError::Command(format!(
+                "{} has not reported GPU {}{}",
+                self.command, gpu, error
+            ))),
+        }
+    }
+
+    /// Waits, for up to the maximum age from the start, until `ready` holds or a run fails.
+    async fn wait_for(&self, ready: impl Fn(&State) -> bool) {
+        let deadline = tokio::time::Instant::from_std(self.started + self.timing.max_age);
+        loop {
+            let updated = self.shared.updated.notified();
+            {
+                let state = self.shared.state.lock().unwrap();
+                if ready(&state) || state.error.is_some() {
+                    return;
+                }
+            }
+            if tokio::time::timeout_at(deadline, updated).await.is_err() {
+                return;
+            }
+        }
+    }
+}
+
+impl Drop for NvidiaSmi {
+    fn drop(&mut self) {
+        // Dropping the task's child kills it.
+        self.task.abort();
+    }
+}
+
+async fn keep_running(command: String, shared: Arc<Shared>, timing: Timing) {
+    loop {
+        let error = match run_once(&command, &shared, timing.query_interval).await {
+            Ok(error) => error,
+            Err(e) => format!("cannot run {}: {}", command, e),
+        };
+        shared.update(|state| state.error = Some(error));
+        tokio::time::sleep(timing.restart_delay).await;
+    }
+}
+
+/// Runs `command` until it exits, recording each reading, and returns why it stopped.
+async fn run_once(
+    command: &str,
+    shared: &Shared,
+    query_interval: Duration,
+) -> std::io::Result<String> {
+    let mut child = Command::new(command)
+        .arg(QUERY)
+        .args(["--format=csv,noheader,nounits", "-lms"])
+        .arg(query_interval.as_millis().to_string())
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .kill_on_drop(true)
+        .spawn()?;
+    let mut lines = BufReader::new(child.stdout.take().unwrap()).lines();
+    // nvidia-smi reports its failures, such as a driver mismatch, on stdout.
+    let mut unexpected: Option<String> = None;
+    while let Some(line) = lines.next().await {
+        let line = line?;
+        match parse_line(&line) {
+            Some((gpu, reading)) => shared.update(|state| {
+                state.readings.insert(gpu, reading);
+                state.error = None;
+            }),
+            None if !line.trim().is_empty() => unexpected = Some(line.trim().to_string()),
+            None => {}
+        }
+    }
+    let status = child.status().await?;
+    Ok(match unexpected {
+        Some(line) => format!("{} exited with {}: {}", command, status, line),
+        None => format!("{} exited with {}", command, status),
+    })
+}
+
+/// Parses `index, temperature.gpu, power.draw, fan.speed`, as in `0, 45, 12.34, 30`. Power
+/// and fan speed read `[N/A]` or `[Not Supported]` on GPUs without them.
+fn parse_line(line: &str) -> Option<(u32, GpuReading)> {
+    let fields: Vec<&str> = line.split(',').map(str::trim).collect();
+    if fields.len() != 4 {
+        return None;
+    }
+    Some((
+        fields[0].parse().ok()?,
+        GpuReading {
+            temperature: fields[1].parse().ok()?,
+            power_draw: fields[2].parse().ok(),
+            fan_speed: fields[3].parse().ok(),
+            at: Instant::now(),
+        },
+    ))
+}
+
+/// Starts `nvidia-smi` the first time a sensor needs it, then shares it between sensors.
+pub struct SharedNvidiaSmi {
+    command: String,
+    running: OnceLock<NvidiaSmi>,
+}
+
+impl SharedNvidiaSmi {
+    pub fn new(command: &str) -> SharedNvidiaSmi {
+        SharedNvidiaSmi {
+            command: command.to_string(),
+            running: OnceLock::new(),
+        }
+    }
+
+    pub fn get(&self) -> &NvidiaSmi {
+        self.running.get_or_init(|| NvidiaSmi::start(&self.command))
+    }
+}
+
+/// One GPU's temperature from a shared `nvidia-smi`.
+pub struct NvidiaSmiGpu<'a> {
+    nvidia_smi: &'a NvidiaSmi,
+    gpu: u32,
+    name: String,
+}
+
+impl<'a> NvidiaSmiGpu<'a> {
+    pub fn new(nvidia_smi: &'a NvidiaSmi, gpu: u32) -> NvidiaSmiGpu<'a> {
+        NvidiaSmiGpu {
+            nvidia_smi,
+            gpu,
+            name: format!("{} GPU {}", nvidia_smi.command(), gpu),
+        }
+    }
+}
+
+impl TemperatureSource for NvidiaSmiGpu<'_> {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn unit(&self) -> Unit {
+        Unit::Celsius
+    }
+
+    fn read(&self) -> ReadFuture<'_> {
+        Box::pin(self.nvidia_smi.temperature(self.gpu))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::os::unix::fs::PermissionsExt;
+    use std::path::Path;
+
+    use super::*;
+
+    const FAST: Timing = Timing {
+        query_interval: Duration::from_millis(50),
+        restart_delay: Duration::from_millis(50),
+        max_age: Duration::from_secs(1),
+    };
+
+    /// An executable shell script in `dir`, so that each test gets its own environment.
+    fn script(dir: &Path, body: &str) -> String {
+        let path = dir.join("nvidia-smi");
+        fs::write(&path, format!("#!/bin/sh\n{}\n", body)).unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+        path.to_str().unwrap().to_string()
+    }
+
+    /// `scripts/fake-nvidia-smi` run with `variables` exported.
+    fn fake(dir: &Path, variables: &[(&str, &str)]) -> String {
+        let exports: String = variables
+            .iter()
+            .map(|(name, value)| format!("export {}='{}'\n", name, value.replace('\'', "'\\''")))
+            .collect();
+        script(
+            dir,
+            &format!(
+                "{}exec '{}/scripts/fake-nvidia-smi' \"$@\"",
+                exports,
+                env!("CARGO_MANIFEST_DIR")
+            ),
+        )
+    }
+
+    #[test]
+    fn parses_query_lines() {
+        let (gpu, reading) = parse_line("0, 45, 12.34, 30").unwrap();
+        assert_eq!(gpu, 0);
+        assert_eq!(reading.temperature, 45.0);
+        assert_eq!(reading.power_draw, Some(12.34));
+        assert_eq!(reading.fan_speed, Some(30.0));
+        let (gpu, reading) = parse_line("1, 52, [N/A], [Not Supported]").unwrap();
+        assert_eq!(gpu, 1);
+        assert_eq!((reading.power_draw, reading.fan_speed), (None, None));
+        for line in [
+            "",
+            "0, 45, 12.34",
+            "0, 45, 12.34, 30, 1",
+            "0, [N/A], 12.34, 30",
+            "GPU 0, 45, 12.34, 30",
+            "NVIDIA-SMI has failed because it couldn't communicate with the NVIDIA driver.",
+        ] {
+            assert!(parse_line(line).is_none(), "{}", line);
+        }
+    }
+
+    #[tokio::test]
+    async fn keeps_the_latest_reading_of_every_gpu() {
+        let dir = tempfile::tempdir().unwrap();
+        let command = fake(
+            dir.path(),
+            &[("FAKE_GPUS", "2"), ("FAKE_TEMPERATURE", "60")],
+        );
+        let nvidia_smi = NvidiaSmi::start_with(&command, FAST);
+        assert_eq!(nvidia_smi.temperature(1).await.unwrap(), 61.0);
+        let readings = nvidia_smi.readings().await.unwrap();
+        let summary: Vec<_> = readings
+            .iter()
+            .map(|(&gpu, r)| (gpu, r.temperature, r.power_draw, r.fan_speed))
+            .collect();
+        assert_eq!(
+            summary,
+            [(0, 60.0, Some(35.2), Some(40.0)), (1, 61.0, None, None)]
+        );
+        assert!(nvidia_smi.temperature(2).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn starts_the_command_again_when_it_exits() {
+        let dir = tempfile::tempdir().unwrap();
+        let command = fake(dir.path(), &[("FAKE_EXIT_AFTER", "1")]);
+        let nvidia_smi = NvidiaSmi::start_with(&command, FAST);
+        let first = nvidia_smi.readings().await.unwrap()[&0].at;
+        // Each run reports once, so later readings come from later runs.
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        let latest = nvidia_smi.readings().await.unwrap()[&0].at;
+        assert!(latest > first);
+        assert_eq!(nvidia_smi.temperature(0).await.unwrap(), 50.0);
+    }
+
+    #[tokio::test]
+    async fn reports_why_the_command_failed() {
+        let dir = tempfile::tempdir().unwrap();
+        let message = "NVIDIA-SMI has failed because it couldn't communicate with the driver";
+        let command = fake(dir.path(), &[("FAKE_FAIL", message)]);
+        let nvidia_smi = NvidiaSmi::start_with(&command, FAST);
+        let error = nvidia_smi.temperature(0).await.unwrap_err().to_string();
+        assert!(error.contains(message), "{}", error);
+        assert!(nvidia_smi.readings().await.is_err());
+
+        let missing = dir.path().join("missing").to_str().unwrap().to_string();
+        let nvidia_smi = NvidiaSmi::start_with(&missing, FAST);
+        let error = nvidia_smi.temperature(0).await.unwrap_err().to_string();
+        assert!(error.contains("cannot run"), "{}", error);
+    }
+
+    #[tokio::test]
+    async fn fails_reads_once_the_latest_reading_is_too_old() {
+        let dir = tempfile::tempdir().unwrap();
+        let command = script(dir.path(), "echo '0, 50, 35.20, 40'\nexec sleep 60");
+        let timing = Timing {
+            max_age: Duration::from_millis(500),
+            ..FAST
+        };
+        let nvidia_smi = NvidiaSmi::start_with(&command, timing);
+        assert_eq!(nvidia_smi.temperature(0).await.unwrap(), 50.0);
+        tokio::time::sleep(Duration::from_millis(700)).await;
+        let error = nvidia_smi.temperature(0).await.unwrap_err().to_string();
+        assert!(error.starts_with("no reading from"), "{}", error);
+        // The stale reading is still shown, with its age.
+        assert!(nvidia_smi.readings().await.unwrap().contains_key(&0));
+    }
+}
@@ -0,0 +1,701 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use derive_more::Display;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+
+use crate::dmi::{compare_versions, DmiInfo};
+use crate::ec::field::RegisterPair;
+use crate::ec::EC_REGISTER_COUNT;
+use crate::fan::{self, CalibrationPoint};
+use crate::regmap::{RegisterDef, RegisterMap};
+
+/// The profile for the laptop this controller was first written for.
+pub const DEFAULT_PROFILE: &str = include_str!("../profiles/default.toml");
+
+/// Profiles compiled into the binary, tried after the installed ones.
+pub const BUILTIN_PROFILES: &[&str] = &[DEFAULT_PROFILE];
+
+/// Where installed profiles are looked for, one `.toml` file each.
+pub const PROFILE_DIR: &str = "/etc/ec-fan-control/profiles";
+
+/// Everything specific to one laptop model: its fans and their registers, and the thermal
+/// zones whose sensors and controllers drive them.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Profile {
+    pub name: String,
+    /// The machines the profile is for; it is picked automatically when any rule matches.
+    #[serde(default, rename = "match", skip_serializing_if = "Vec::is_empty")]
+    pub matches: Vec<MatchRule>,
+    #[serde(default = "default_polling_interval_ms")]
+    pub polling_interval_ms: u64,
+    pub fans: Vec<FanProfile>,
+    pub zones: Vec<ZoneProfile>,
+    /// Extra register names, as in a `--register-map` TOML file.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub registers: BTreeMap<String, RegisterDef>,
+}
+
+fn default_polling_interval_ms() -> u64 {
+    5000
+}
+
+/// DMI values a machine must have. Unset fields match anything, and the BIOS version
+/// bounds are inclusive, since EC layouts change between firmware releases.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MatchRule {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sys_vendor: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub product_name: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub board_name: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bios_version_min: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bios_version_max: Option<String>,
+}
+
+impl MatchRule {
+    pub fn matches(&self, dmi: &DmiInfo) -> bool {
+        let same = |expected: &Option<String>, actual: &str| {
+            expected.as_ref().is_none_or(|e| e.trim() == actual)
+        };
+        same(&self.sys_vendor, &dmi.sys_vendor)
+            && same(&self.product_name, &dmi.product_name)
+            && same(&self.board_name, &dmi.board_name)
+            && self.bios_version_min.as_ref().is_none_or(|min| {
+                compare_versions(&dmi.bios_version, min) != std::cmp::Ordering::Less
+            })
+            && self.bios_version_max.as_ref().is_none_or(|max| {
+                compare_versions(&dmi.bios_version, max) != std::cmp::Ordering::Greater
+            })
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FanProfile {
+    /// Used to refer to the fan from zones, and upper-cased as the prefix of its register names.
+    pub name: String,
+    pub control_register: u64,
+    /// Written to the control register to take the fan over from the EC.
+    pub acquire_control: u8,
+    /// Written to the control register to hand the fan back on exit.
+    pub release_control: u8,
+    pub speed_register: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rpm_registers: Option<RegisterPair>,
+    /// Where the EC keeps its own reading for the part this fan cools, if known.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub temperature_register: Option<u64>,
+    /// Speed commands at known duties, in increasing order of duty; the command for any
+    /// other duty is interpolated between them.
+    pub calibration: Vec<CalibrationPoint>,
+}
+
+/// Something to keep cool: its temperature is the hottest of its sensors, and its controller
+/// drives its fans. A fan in several zones follows whichever asks for the most cooling.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ZoneProfile {
+    pub name: String,
+    pub sensors: Vec<SensorProfile>,
+    /// Sensors read only when none of `sensors` can be, such as the EC's reading for a GPU
+    /// whose driver is not loaded.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub fallback: Vec<SensorProfile>,
+    /// Names of the fans this zone drives.
+    pub fans: Vec<String>,
+    pub controller: ControllerProfile,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SensorProfile {
+    /// A `temp` file under `/sys/class/thermal`, in millidegrees.
+    ThermalZone(String),
+    /// The first thermal zone of the first of these types present, such as
+    /// `["x86_pkg_temp", "TCPU", "acpitz"]`. Unlike a path, this survives zones being
+    /// renumbered by a kernel upgrade or a change in module load order.
+    ThermalZoneType(Vec<String>),
+    /// Channels of a hwmon device under `/sys/class/hwmon`.
+    Hwmon(HwmonProfile),
+    /// The first GPU reported by `nvidia-smi`.
+    NvidiaSmi,
+    /// A GPU by its `nvidia-smi` index.
+    NvidiaSmiGpu(u32),
+    /// The EC's own reading, which is what the firmware's fan curve goes by.
+    EcRegister(EcRegisterProfile),
+}
+
+/// An EC register or named field holding a temperature, such as `CPU_TEMPERATURE`.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct EcRegisterProfile {
+    /// A register name or number.
+    pub register: String,
+    /// Degrees per unit of the raw value.
+    #[serde(default = "default_scale")]
+    pub scale: f64,
+    /// Degrees added after scaling.
+    #[serde(default)]
+    pub offset: f64,
+}
+
+fn default_scale() -> f64 {
+    1.0
+}
+
+/// Picks hwmon channels by driver and label, such as coretemp's `Core \d+` or k10temp's
+/// `Tctl`, since hwmon devices are numbered in probe order too.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct HwmonProfile {
+    /// The device's `name`, such as `coretemp`, `k10temp`, `nvme` or `amdgpu`.
+    pub driver: String,
+    /// A regex searched for in each channel's `temp*_label`; without one, every temperature
+    /// channel of the device counts.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub label: Option<String>,
+    /// How the readings of several matching channels are combined.
+    #[serde(default)]
+    pub aggregate: Aggregate,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Display, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Aggregate {
+    #[default]
+    #[display(fmt = "max")]
+    Max,
+    #[display(fmt = "avg")]
+    Avg,
+}
+
+impl Aggregate {
+    pub fn apply(self, readings: &[f64]) -> Option<f64> {
+        if readings.is_empty() {
+            return None;
+        }
+        Some(match self {
+            Aggregate::Max => readings.iter().copied().fold(f64::MIN, f64::max),
+            Aggregate::Avg => readings.iter().sum::<f64>() / readings.len() as f64,
+        })
+    }
+}
+
+impl fmt::Display for SensorProfile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SensorProfile::ThermalZone(path) => write!(f, "thermal_zone {}", path),
+            SensorProfile::ThermalZoneType(types) => {
+                write!(f, "thermal_zone_type {}", types.join(", "))
+            }
+            SensorProfile::Hwmon(hwmon) => write!(
+                f,
+                "hwmon {} {}",
+                hwmon.driver,
+                hwmon.label.as_deref().unwrap_or("*")
+            ),
+            SensorProfile::NvidiaSmi => write!(f, "nvidia_smi"),
+            SensorProfile::NvidiaSmiGpu(gpu) => write!(f, "nvidia_smi_gpu {}", gpu),
+            SensorProfile::EcRegister(ec) => write!(f, "ec_register {}", ec.register),
+        }
+    }
+}
+
+/// PID controller parameters. The controller output is taken as a duty in percent, clamped
+/// to 0-100%, which each fan turns into a command through its calibration table.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ControllerProfile {
+    /// Temperature the controller steers towards, in degrees.
+    pub target: f64,
+    pub proportional_gain: f64,
+    pub integral_gain: f64,
+    pub derivative_gain: f64,
+    /// How many readings the integral and derivative terms are taken over.
+    pub history: usize,
+}
+
+#[derive(Display, Debug)]
+pub enum ProfileError {
+    #[display(fmt = "{}", _0)]
+    Io(io::Error),
+    #[display(fmt = "{}", _0)]
+    Toml(toml::de::Error),
+    #[display(fmt = "invalid profile: {}", _0)]
+    Invalid(String),
+    #[display(fmt = "{}: {}", path, error)]
+    File {
+        path: String,
+        error: Box<ProfileError>,
+    },
+    #[display(fmt = "{}", _0)]
+    WrongMachine(String),
+}
+
+impl std::error::Error for ProfileError {}
+
+impl From<io::Error> for ProfileError {
+    fn from(e: io::Error) -> ProfileError {
+        ProfileError::Io(e)
+    }
+}
+
+impl Profile {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Profile, ProfileError> {
+        let path = path.as_ref();
+        fs::read_to_string(path)
+            .map_err(ProfileError::from)
+            .and_then(|text| Profile::parse(&text))
+            .map_err(|e| ProfileError::File {
+                path: path.display().to_string(),
+                error: Box::new(e),
+            })
+    }
+
+    pub fn parse(text: &str) -> Result<Profile, ProfileError> {
+        let profile: Profile = toml::from_str(text).map_err(ProfileError::Toml)?;
+        profile.validate().map_err(ProfileError::Invalid)?;
+        Ok(profile)
+    }
+
+    pub fn builtin() -> Result<Profile, ProfileError> {
+        Profile::parse(DEFAULT_PROFILE)
+    }
+
+    pub fn matches(&self, dmi: &DmiInfo) -> bool {
+        self.matches.iter().any(|rule| rule.matches(dmi))
+    }
+
+    pub fn fan(&self, name: &str) -> Option<&FanProfile> {
+        self.fans.iter().find(|fan| fan.name == name)
+    }
+
+    /// The zones that drive `fan`.
+    pub fn zones_driving<'a>(&'a self, fan: &'a str) -> impl Iterator<Item = &'a ZoneProfile> {
+        self.zones
+            .iter()
+            .filter(move |zone| zone.fans.iter().any(|name| name == fan))
+    }
+
+    /// Names for every register the profile uses, such as `CPU_CONTROL` and `CPU_FAN_RPM`,
+    /// plus any it lists under `registers`.
+    pub fn register_map(&self) -> RegisterMap {
+        let mut map = RegisterMap::default();
+        for fan in &self.fans {
+            let name = |suffix: &str| format!("{}_{}", fan.name.to_uppercase(), suffix);
+            map.insert(
+                &name("CONTROL"),
+                RegisterDef::register(fan.control_register),
+            );
+            map.insert(
+                &name("SPEED_CONTROL"),
+                RegisterDef::register(fan.speed_register),
+            );
+            if let Some(register_offset) = fan.temperature_register {
+                map.insert(&name("TEMPERATURE"), RegisterDef::register(register_offset));
+            }
+            if let Some(pair) = fan.rpm_registers {
+                if pair.high == pair.low + 1 {
+                    let def = RegisterDef {
+                        bits: 16,
+                        writable: false,
+                        ..RegisterDef::register(pair.low)
+                    };
+                    map.insert(&name("FAN_RPM"), def);
+                } else {
+                    map.insert(&name("FAN_RPM_LOW"), RegisterDef::register(pair.low));
+                    map.insert(&name("FAN_RPM_HIGH"), RegisterDef::register(pair.high));
+                }
+            }
+        }
+        for (name, def) in &self.registers {
+            map.insert(name, def.clone());
+        }
+        map
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if self.name.is_empty() {
+            return Err("name is empty".to_string());
+        }
+        if let Some(rule) = self.matches.iter().find(|rule| {
+            rule.sys_vendor.is_none() && rule.product_name.is_none() && rule.board_name.is_none()
+        }) {
+            return Err(format!(
+                "match rule {:?} needs a sys_vendor, product_name or board_name",
+                rule
+            ));
+        }
+        if self.polling_interval_ms == 0 {
+            return Err("polling_interval_ms must be above zero".to_string());
+        }
+        if self.fans.is_empty() {
+            return Err("there are no fans".to_string());
+        }
+        for (i, fan) in self.fans.iter().enumerate() {
+            if self.fans[..i].iter().any(|other| other.name == fan.name) {
+                return Err(format!("there are two fans named '{}'", fan.name));
+            }
+            fan.validate()
+                .map_err(|e| format!("fan '{}': {}", fan.name, e))?;
+            if self.zones_driving(&fan.name).next().is_none() {
+                return Err(format!("no zone drives fan '{}'", fan.name));
+            }
+        }
+        for (i, zone) in self.zones.iter().enumerate() {
+            if self.zones[..i].iter().any(|other| other.name == zone.name) {
+                return Err(format!("there are two zones named '{}'", zone.name));
+            }
+            zone.validate(self)
+                .map_err(|e| format!("zone '{}': {}", zone.name, e))?;
+        }
+        for (name, def) in &self.registers {
+            def.validate(name)
+                .map_err(|e| format!("registers: {}", e))?;
+        }
+        Ok(())
+    }
+}
+
+impl FanProfile {
+    pub fn command_for_duty(&self, duty: f64) -> u8 {
+        fan::command_for_duty(&self.calibration, duty)
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        if self.name.is_empty()
+            || !self
+                .name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '_')
+        {
+            return Err("names may only have letters, digits and underscores".to_string());
+        }
+        let mut registers = vec![
+            ("control_register", self.control_register),
+            ("speed_register", self.speed_register),
+        ];
+        if let Some(pair) = self.rpm_registers {
+            registers.push(("rpm_registers.low", pair.low));
+            registers.push(("rpm_registers.high", pair.high));
+        }
+        if let Some(register_offset) = self.temperature_register {
+            registers.push(("temperature_register", register_offset));
+        }
+        for (key, register_offset) in registers {
+            if register_offset >= EC_REGISTER_COUNT {
+                return Err(format!(
+                    "{} {:#x} is not an EC register",
+                    key, register_offset
+                ));
+            }
+        }
+        if self.calibration.len() < 2 {
+            return Err("calibration needs at least two points".to_string());
+        }
+        if self
+            .calibration
+            .iter()
+            .any(|p| !(0.0..=100.0).contains(&p.duty))
+        {
+            return Err("calibration duties must be between 0 and 100%".to_string());
+        }
+        if self
+            .calibration
+            .windows(2)
+            .any(|pair| pair[0].duty >= pair[1].duty)
+        {
+            return Err("calibration must be in increasing order of duty".to_string());
+        }
+        Ok(())
+    }
+}
+
+impl ZoneProfile {
+    fn validate(&self, profile: &Profile) -> Result<(), String> {
+        if self.sensors.is_empty() {
+            return Err("there are no sensors".to_string());
+        }
+        if self.fans.is_empty() {
+            return Err("there are no fans".to_string());
+        }
+        if let Some(fan) = self.fans.iter().find(|&fan| profile.fan(fan).is_none()) {
+            return Err(format!("there is no fan named '{}'", fan));
+        }
+        let register_map = profile.register_map();
+        for sensor in self.sensors.iter().chain(&self.fallback) {
+            match sensor {
+                SensorProfile::EcRegister(ec) => {
+                    register_map
+                        .resolve(&ec.register)
+                        .map_err(|e| format!("sensor: {}", e))?;
+                    if !ec.scale.is_finite() || !ec.offset.is_finite() {
+                        return Err("sensor scale and offset must be numbers".to_string());
+                    }
+                }
+                SensorProfile::Hwmon(HwmonProfile {
+                    label: Some(label), ..
+                }) => {
+                    Regex::new(label).map_err(|e| format!("sensor label: {}", e))?;
+                }
+                SensorProfile::ThermalZoneType(types) if types.is_empty() => {
+                    return Err("thermal_zone_type needs at least one type".to_string());
+                }
+                _ => {}
+            }
+        }
+        if self.controller.history == 0 {
+            return Err("controller.history must be at least 1".to_string());
+        }
+        if !self.controller.target.is_finite() {
+            return Err("controller.target must be a number".to_string());
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Selection {
+    /// Given with `--profile` and not restricted to particular machines.
+    Chosen,
+    /// One of the profile's match rules fits this machine.
+    Matched,
+    /// Nothing fits this machine; either a `--profile` written for other machines or, without
+    /// one, the default profile, which is good enough for naming registers but not for
+    /// writing to them.
+    Unmatched,
+}
+
+pub struct SelectedProfile {
+    pub profile: Profile,
+    /// The file it came from, or `built-in`.
+    pub source: String,
+    pub selection: Selection,
+    pub dmi: DmiInfo,
+}
+
+impl SelectedProfile {
+    /// Fails unless the profile is meant for this machine or `force` is set, so that a profile
+    /// for another machine does not write garbage into the EC.
+    pub fn check_machine(&self, force: bool) -> Result<(), ProfileError> {
+        if self.selection != Selection::Unmatched || force {
+            return Ok(());
+        }
+        Err(ProfileError::WrongMachine(match self.source.as_str() {
+            "built-in" => format!(
+                "no machine profile matches {}; pass --profile with a profile for this \
+                 machine, or --force to use '{}' anyway. A profile is picked for this machine \
+                 by a rule such as `[[match]] sys_vendor = \"{}\" product_name = \"{}\"`",
+                self.dmi, self.profile.name, self.dmi.sys_vendor, self.dmi.product_name
+            ),
+            source => format!(
+                "profile '{}' ({}) does not match {}; pass --force to use it anyway",
+                self.profile.name, source, self.dmi
+            ),
+        }))
+    }
+}
+
+/// Every profile that can be picked automatically: the installed ones in `dir`, in file name
+/// order, followed by the built-in ones. A missing `dir` has no profiles, and an installed
+/// profile that does not load is skipped with a warning rather than spoiling the rest.
+pub fn available_profiles<P: AsRef<Path>>(dir: P) -> Result<Vec<(String, Profile)>, ProfileError> {
+    let mut paths: Vec<_> = match fs::read_dir(dir) {
+        Ok(entries) => entries
+            .map(|entry| entry.map(|e| e.path()))
+            .collect::<io::Result<_>>()?,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+        Err(e) => return Err(e.into()),
+    };
+    paths.retain(|path| path.extension().is_some_and(|e| e == "toml"));
+    paths.sort();
+    let mut profiles = Vec::new();
+    for path in paths {
+        match Profile::load(&path) {
+            Ok(profile) => profiles.push((path.display().to_string(), profile)),
+            Err(e) => eprintln!("Skipping profile {}", e),
+        }
+    }
+    for text in BUILTIN_PROFILES {
+        profiles.push(("built-in".to_string(), Profile::parse(text)?));
+    }
+    Ok(profiles)
+}
+
+/// Picks the profile given with `--profile`, or else the first available one whose match
+/// rules fit `dmi`, falling back to the default profile.
+pub fn select<P: AsRef<Path>>(
+    explicit: Option<&str>,
+    dir: P,
+    dmi: DmiInfo,
+) -> Result<SelectedProfile, ProfileError> {
+    let (source, profile, selection) = match explicit {
+        Some(path) => {
+            let profile = Profile::load(path)?;
+            let selection = if profile.matches.is_empty() {
+                Selection::Chosen
+            } else if profile.matches(&dmi) {
+                Selection::Matched
+            } else {
+                Selection::Unmatched
+            };
+            (path.to_string(), profile, selection)
+        }
+        None => match available_profiles(dir)?
+            .into_iter()
+            .find(|(_, profile)| profile.matches(&dmi))
+        {
+            Some((source, profile)) => (source, profile, Selection::Matched),
+            None => (
+                "built-in".to_string(),
+                Profile::builtin()?,
+                Selection::Unmatched,
+            ),
+        },
+    };
+    Ok(SelectedProfile {
+        profile,
+        source,
+        selection,
+        dmi,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dmi(bios_version: &str) -> DmiInfo {
+        DmiInfo {
+            sys_vendor: "Example".to_string(),
+            product_name: "Gaming 15".to_string(),
+            board_name: "EX15".to_string(),
+            bios_version: bios_version.to_string(),
+        }
+    }
+
+    #[test]
+    fn a_rule_without_fields_matches_any_machine() {
+        assert!(MatchRule::default().matches(&dmi("1.07")));
+        assert!(MatchRule::default().matches(&DmiInfo::default()));
+    }
+
+    #[test]
+    fn matches_every_field_given() {
+        let rule = MatchRule {
+            sys_vendor: Some("Example".to_string()),
+            product_name: Some(" Gaming 15 ".to_string()),
+            ..MatchRule::default()
+        };
+        assert!(rule.matches(&dmi("1.07")));
+        let other = DmiInfo {
+            product_name: "Gaming 17".to_string(),
+            ..dmi("1.07")
+        };
+        assert!(!rule.matches(&other));
+        let rule = MatchRule {
+            board_name: Some("ex15".to_string()),
+            ..rule
+        };
+        assert!(!rule.matches(&dmi("1.07")));
+    }
+
+    #[test]
+    fn matches_bios_versions_within_inclusive_bounds() {
+        let rule = MatchRule {
+            bios_version_min: Some("1.07".to_string()),
+            bios_version_max: Some("1.10".to_string()),
+            ..MatchRule::default()
+        };
+        for (version, matches) in [
+            ("1.06", false),
+            ("1.07", true),
+            ("1.7", true),
+            ("1.9", true),
+            ("1.10", true),
+            ("1.10a", false),
+            ("1.11", false),
+            ("1", false),
+            ("", false),
+        ] {
+            assert_eq!(rule.matches(&dmi(version)), matches, "{}", version);
+        }
+        let from = MatchRule {
+            bios_version_max: None,
+            ..rule.clone()
+        };
+        assert!(from.matches(&dmi("2.01")));
+        let up_to = MatchRule {
+            bios_version_min: None,
+            ..rule
+        };
+        assert!(up_to.matches(&dmi("0.9")));
+    }
+
+    #[test]
+    fn names_the_registers_of_the_builtin_profile() {
+        let map = Profile::builtin().unwrap().register_map();
+        let names: Vec<(&str, u64)> = map.iter().map(|(name, def)| (name, def.offset)).collect();
+        assert_eq!(
+            names,
+            [
+                ("CPU_CONTROL", 0xf4),
+                ("CPU_FAN_RPM", 0xb2),
+                ("CPU_SPEED_CONTROL", 0xf4),
+                ("CPU_TEMPERATURE", 0x58),
+                ("GPU_CONTROL", 0x89),
+                ("GPU_SPEED_CONTROL", 0xb7),
+            ]
+        );
+    }
+
+    #[test]
+    fn rejects_registers_outside_the_ec() {
+        let text = DEFAULT_PROFILE.replace("speed_register = 0xb7", "speed_register = 0x1b7");
+        match Profile::parse(&text) {
+            Err(ProfileError::Invalid(e)) => {
+                assert_eq!(e, "fan 'gpu': speed_register 0x1b7 is not an EC register")
+            }
+            other => panic!(
+                "expected an invalid profile, got {:?}",
+                other.map(|p| p.name)
+            ),
+        }
+    }
+
+    #[test]
+    fn skips_installed_profiles_that_do_not_load() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("a-broken.toml"), "fans = [").unwrap();
+        let mut good = Profile::builtin().unwrap();
+        good.name = "installed".to_string();
+        fs::write(
+            dir.path().join("b-good.toml"),
+            toml::to_string(&good).unwrap(),
+        )
+        .unwrap();
+        let names: Vec<String> = available_profiles(dir.path())
+            .unwrap()
+            .into_iter()
+            .map(|(_, profile)| profile.name)
+            .collect();
+        assert_eq!(names, ["installed", "default"]);
+    }
+
+    #[test]
+    fn a_missing_profile_dir_has_only_the_builtin_profiles() {
+        let dir = tempfile::tempdir().unwrap();
+        let profiles = available_profiles(dir.path().join("missing")).unwrap();
+        assert_eq!(profiles.len(), BUILTIN_PROFILES.len());
+    }
+}
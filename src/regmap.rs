@@ -0,0 +1,412 @@
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::fmt::Write;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use derive_more::Display;
+use serde::{Deserialize, Serialize};
+
+use crate::dsdt::EcFieldDef;
+use crate::ec::{parse_register_number, EcBackend, EC_REGISTER_COUNT};
+
+/// A named register, or run of bits, in the EC register space.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RegisterDef {
+    pub offset: u64,
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub bit: u8,
+    #[serde(default = "default_bits")]
+    pub bits: u64,
+    #[serde(default = "default_writable")]
+    pub writable: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+}
+
+fn is_zero(n: &u8) -> bool {
+    *n == 0
+}
+
+fn default_bits() -> u64 {
+    8
+}
+
+fn default_writable() -> bool {
+    true
+}
+
+impl RegisterDef {
+    pub fn register(offset: u64) -> RegisterDef {
+        RegisterDef {
+            offset,
+            bit: 0,
+            bits: 8,
+            writable: true,
+            description: None,
+        }
+    }
+
+    /// A whole register rather than a field within one or across several.
+    pub fn is_whole_register(&self) -> bool {
+        self.bit == 0 && self.bits == 8
+    }
+
+    /// Registers spanned by this definition.
+    pub fn register_offsets(&self) -> std::ops::Range<u64> {
+        self.offset..self.offset + (self.bit as u64 + self.bits).div_ceil(8)
+    }
+
+    /// Extracts the value from the registers it spans, taken as little-endian bytes.
+    pub fn decode(&self, registers: &[u8]) -> u64 {
+        let value = registers
+            .iter()
+            .rev()
+            .fold(0u64, |acc, &b| acc << 8 | b as u64);
+        let mask = u32::try_from(self.bits)
+            .ok()
+            .and_then(|bits| 1u64.checked_shl(bits))
+            .map_or(u64::MAX, |m| m - 1);
+        value.checked_shr(self.bit as u32).unwrap_or(0) & mask
+    }
+
+    /// Extracts the value from a full dump of the register space.
+    pub fn decode_from(&self, all_registers: &[u8]) -> u64 {
+        let range = self.register_offsets();
+        self.decode(&all_registers[range.start as usize..range.end as usize])
+    }
+
+    pub fn read(&self, ec: &dyn EcBackend) -> io::Result<u64> {
+        let mut buf = vec![0u8; self.register_offsets().count()];
+        ec.read_registers(self.offset, &mut buf)?;
+        Ok(self.decode(&buf))
+    }
+
+    pub fn validate(&self, name: &str) -> Result<(), String> {
+        if self.bit > 7 || self.bits == 0 || self.bits > 64 {
+            return Err(format!("{} has an invalid bit range", name));
+        }
+        let end = self
+            .offset
+            .checked_add((self.bit as u64 + self.bits).div_ceil(8));
+        if end.is_none_or(|end| end > EC_REGISTER_COUNT) {
+            return Err(format!("{} runs past the end of the EC", name));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Display, Debug)]
+pub enum RegisterMapError {
+    #[display(fmt = "{}", _0)]
+    Io(io::Error),
+    #[display(fmt = "line {}: {}", line, message)]
+    Parse { line: usize, message: String },
+    #[display(fmt = "{}", _0)]
+    Toml(toml::de::Error),
+    #[display(fmt = "{}", _0)]
+    Invalid(String),
+    #[display(fmt = "unknown register '{}'", _0)]
+    UnknownRegister(String),
+}
+
+impl std::error::Error for RegisterMapError {}
+
+impl From<io::Error> for RegisterMapError {
+    fn from(e: io::Error) -> RegisterMapError {
+        RegisterMapError::Io(e)
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct RegisterMapFile {
+    registers: BTreeMap<String, RegisterDef>,
+}
+
+/// Register names, so commands and logs can say `RPM1` or `CTYP` rather than raw offsets.
+#[derive(Clone, Debug, Default)]
+pub struct RegisterMap {
+    registers: BTreeMap<String, RegisterDef>,
+}
+
+impl RegisterMap {
+    /// The fields as a map, leaving out those a map cannot hold, such as buffers wider than 64
+    /// bits, with the reasons.
+    pub fn from_dsdt(fields: &[EcFieldDef]) -> (RegisterMap, Vec<String>) {
+        let mut map = RegisterMap::default();
+        let mut left_out = Vec::new();
+        for f in fields {
+            let def = RegisterDef {
+                offset: f.register_offset(),
+                bit: f.first_bit() as u8,
+                bits: f.bit_width,
+                writable: true,
+                description: None,
+            };
+            match def.validate(&f.name) {
+                Ok(()) => map.insert(&f.name, def),
+                Err(e) => left_out.push(format!("{} ({} bits)", e, f.bit_width)),
+            }
+        }
+        (map, left_out)
+    }
+
+    /// Loads a `.toml` map, or anything else in the `ReadRegister 0xb2 RPM1 8, RPM2 8` style.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<RegisterMap, RegisterMapError> {
+        let path = path.as_ref();
+        let text = fs::read_to_string(path)
+            .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path.display(), e)))?;
+        if path.extension().is_some_and(|e| e == "toml") {
+            RegisterMap::parse_toml(&text)
+        } else {
+            RegisterMap::parse_lines(&text)
+        }
+    }
+
+    pub fn parse_toml(text: &str) -> Result<RegisterMap, RegisterMapError> {
+        let file: RegisterMapFile = toml::from_str(text).map_err(RegisterMapError::Toml)?;
+        for (name, def) in &file.registers {
+            def.validate(name).map_err(RegisterMapError::Invalid)?;
+        }
+        Ok(RegisterMap {
+            registers: file.registers,
+        })
+    }
+
+    /// Parses lines of `[Read|Write]Register <offset> <entry>, <entry>...` where an entry is
+    /// `NAME [bits] [description]` or just `bits` for unnamed padding. Entries are laid out
+    /// one after another from bit 0 of the offset; `#` starts a comment.
+    pub fn parse_lines(text: &str) -> Result<RegisterMap, RegisterMapError> {
+        let mut map = RegisterMap::default();
+        for (i, line) in text.lines().enumerate() {
+            let error = |message: String| RegisterMapError::Parse {
+                line: i + 1,
+                message,
+            };
+            let line = line.split('#').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+            let mut words = line.splitn(3, char::is_whitespace);
+            let writable = match words.next() {
+                Some("ReadRegister") => false,
+                Some("WriteRegister") | Some("Register") => true,
+                Some(other) => return Err(error(format!("unexpected '{}'", other))),
+                None => continue,
+            };
+            let offset = words
+                .next()
+                .and_then(|w| parse_register_number(w).ok())
+                .ok_or_else(|| error("expected a register offset".to_string()))?;
+            let mut bit_cursor = offset
+                .checked_mul(8)
+                .ok_or_else(|| error(format!("register offset {:#x} is out of range", offset)))?;
+            for entry in words.next().unwrap_or("").split(',').map(str::trim) {
+                let mut parts = entry.split_whitespace();
+                let (name, bits, description) = match parts.next() {
+                    None => continue,
+                    Some(first) => match parse_register_number(first) {
+                        Ok(bits) => (None, bits, Vec::new()),
+                        Err(_) => {
+                            let rest: Vec<&str> = parts.collect();
+                            match rest.first().map(|w| parse_register_number(w)) {
+                                Some(Ok(bits)) => (Some(first), bits, rest[1..].to_vec()),
+                                _ => (Some(first), 8, rest),
+                            }
+                        }
+                    },
+                };
+                if let Some(name) = name {
+                    let def = RegisterDef {
+                        offset: bit_cursor / 8,
+                        bit: (bit_cursor % 8) as u8,
+                        bits,
+                        writable,
+                        description: Some(description.join(" ")).filter(|d| !d.is_empty()),
+                    };
+                    def.validate(name).map_err(error)?;
+                    map.registers.insert(name.to_string(), def);
+                }
+                bit_cursor = bit_cursor
+                    .checked_add(bits)
+                    .ok_or_else(|| error(format!("{} bits run past the end", bits)))?;
+            }
+        }
+        Ok(map)
+    }
+
+    pub fn insert(&mut self, name: &str, def: RegisterDef) {
+        self.registers.insert(name.to_string(), def);
+    }
+
+    /// Adds `other`'s names, replacing any already defined here.
+    pub fn merge(&mut self, other: RegisterMap) {
+        self.registers.extend(other.registers);
+    }
+
+    pub fn get(&self, name: &str) -> Option<&RegisterDef> {
+        self.registers.get(name)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &RegisterDef)> {
+        self.registers
+            .iter()
+            .map(|(name, def)| (name.as_str(), def))
+    }
+
+    /// Resolves a register name, or a number such as `0xb2`, to its register offset.
+    pub fn resolve(&self, register: &str) -> Result<u64, RegisterMapError> {
+        if let Ok(offset) = parse_register_number(register) {
+            if offset < EC_REGISTER_COUNT {
+                return Ok(offset);
+            }
+        }
+        self.get(register)
+            .map(|def| def.offset)
+            .ok_or_else(|| RegisterMapError::UnknownRegister(register.to_string()))
+    }
+
+    /// Every name that covers `register_offset`.
+    pub fn names_at(&self, register_offset: u64) -> Vec<&str> {
+        self.iter()
+            .filter(|(_, def)| def.register_offsets().contains(&register_offset))
+            .map(|(name, _)| name)
+            .collect()
+    }
+
+    pub fn label(&self, register_offset: u64) -> String {
+        self.names_at(register_offset).join(", ")
+    }
+
+    pub fn to_toml(&self) -> String {
+        toml::to_string(&RegisterMapFile {
+            registers: self.registers.clone(),
+        })
+        .unwrap()
+    }
+
+    /// Renders in the line format, grouping names that start in the same register.
+    pub fn to_lines(&self) -> String {
+        let mut by_offset: BTreeMap<u64, Vec<(&str, &RegisterDef)>> = BTreeMap::new();
+        for (name, def) in self.iter() {
+            by_offset.entry(def.offset).or_default().push((name, def));
+        }
+        let mut out = String::new();
+        for (offset, mut defs) in by_offset {
+            defs.sort_by_key(|(_, def)| def.bit);
+            let keyword = if defs.iter().all(|(_, def)| def.writable) {
+                "Register"
+            } else {
+                "ReadRegister"
+            };
+            let mut entries = Vec::new();
+            let mut bit_cursor = 0;
+            for (name, def) in defs {
+                if def.bit < bit_cursor {
+                    // Overlaps the previous entry, so it needs a line of its own.
+                    writeln!(
+                        out,
+                        "{} {:#04x} {}, {} {}",
+                        keyword, offset, def.bit, name, def.bits
+                    )
+                    .unwrap();
+                    continue;
+                }
+                if def.bit > bit_cursor {
+                    entries.push((def.bit - bit_cursor).to_string());
+                }
+                let mut entry = format!("{} {}", name, def.bits);
+                if let Some(description) = &def.description {
+                    write!(entry, " {}", description).unwrap();
+                }
+                entries.push(entry);
+                bit_cursor = def.bit + def.bits.min(8) as u8;
+            }
+            writeln!(out, "{} {:#04x} {}", keyword, offset, entries.join(", ")).unwrap();
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lays_out_line_entries_from_the_offset() {
+        let map =
+            RegisterMap::parse_lines("ReadRegister 0xb2 RPM1 8, RPM2 8\nRegister 0x40 2, ACCC 1")
+                .unwrap();
+        assert_eq!(map.resolve("RPM2").unwrap(), 0xb3);
+        let accc = map.get("ACCC").unwrap();
+        assert_eq!((accc.offset, accc.bit, accc.bits), (0x40, 2, 1));
+    }
+
+    #[test]
+    fn rejects_offsets_that_overflow() {
+        for text in [
+            "ReadRegister 0xffffffffffffffff RPM1 8",
+            "ReadRegister 0x40 0xffffffffffffffff, RPM1 8",
+        ] {
+            match RegisterMap::parse_lines(text) {
+                Err(RegisterMapError::Parse { line: 1, .. }) => {}
+                other => panic!(
+                    "{}: expected a parse error, got {:?}",
+                    text,
+                    other.map(|_| ())
+                ),
+            }
+        }
+    }
+
+    #[test]
+    fn masks_fields_of_any_width() {
+        let registers = [0xa5, 0x5a, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff];
+        let def = |bit, bits| RegisterDef {
+            bit,
+            bits,
+            ..RegisterDef::register(0)
+        };
+        assert_eq!(def(4, 4).decode(&registers[..1]), 0xa);
+        assert_eq!(def(0, 16).decode(&registers[..2]), 0x5aa5);
+        assert_eq!(def(0, 64).decode(&registers), 0xffff_ffff_ffff_5aa5);
+        assert_eq!(def(0, 0).decode(&registers[..1]), 0);
+        assert_eq!(def(0, 256).decode(&registers), 0xffff_ffff_ffff_5aa5);
+        assert_eq!(def(200, 8).decode(&registers[..1]), 0);
+        assert!(def(0, 0).validate("X").is_err());
+        assert!(def(0, 65).validate("X").is_err());
+        assert!(RegisterDef::register(u64::MAX).validate("X").is_err());
+    }
+
+    #[test]
+    fn writes_dsdt_fields_as_maps_that_load_again() {
+        let field = |name: &str, bit_offset, bit_width| EcFieldDef {
+            name: name.to_string(),
+            region: "ERAM".to_string(),
+            bit_offset,
+            bit_width,
+        };
+        let fields = [
+            field("SW2S", 0x40 * 8, 1),
+            field("ACCC", 0x40 * 8 + 3, 1),
+            field("EMPT", 0x41 * 8, 0),
+            field("BTSN", 0x48 * 8, 32),
+            field("BUF0", 0x60 * 8, 256),
+            field("RPM1", 0xb2 * 8, 8),
+        ];
+        let (map, left_out) = RegisterMap::from_dsdt(&fields);
+        assert_eq!(left_out.len(), 2);
+        assert!(left_out[0].starts_with("EMPT has an invalid bit range"));
+        assert!(left_out[1].starts_with("BUF0 has an invalid bit range"));
+        let names: Vec<&str> = map.iter().map(|(name, _)| name).collect();
+        assert_eq!(names, ["ACCC", "BTSN", "RPM1", "SW2S"]);
+
+        for loaded in [
+            RegisterMap::parse_lines(&map.to_lines()).unwrap(),
+            RegisterMap::parse_toml(&map.to_toml()).unwrap(),
+        ] {
+            assert_eq!(loaded.registers, map.registers);
+        }
+    }
+}
@@ -0,0 +1,426 @@
+use std::collections::BTreeMap;
+use std::io::{self, BufRead, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
+use std::thread;
+use std::time::Duration;
+
+use crate::ec::field::BitField;
+use crate::ec::{parse_register_number, EcBackend, EC_REGISTER_COUNT};
+use crate::regmap::RegisterMap;
+use crate::watch::parse_register_ranges;
+use crate::{read_from_ec_register, write_to_ec_register};
+
+const HELP: &str = "\
+read <register> [count]           show registers in hex, decimal and binary
+write <register> <value>          write a register
+bits <register>                   show a register bit by bit
+bits <register> <bit>[:<width>] <value>
+                                  set bits, leaving the rest of the register alone
+watch <registers> [interval_ms]   print changes until Enter is pressed
+undo                              revert the most recent write
+touched                           list registers written this session
+help                              show this help
+quit                              restore every touched register and leave
+
+Registers are numbers such as 0xb2 or 178, or names from the register map; watch also
+takes ranges such as 0x40-0x4f,0xb2. Reading a named field shows its value too.
+";
+
+/// An interactive shell for poking the EC which remembers the original value of every
+/// register it writes and puts them all back when it ends.
+pub struct Session<'a> {
+    ec: &'a dyn EcBackend,
+    register_map: &'a RegisterMap,
+    should_exit: &'a AtomicBool,
+    lines: Receiver<String>,
+    originals: BTreeMap<u64, u8>,
+    undo: Vec<(u64, u8)>,
+}
+
+impl<'a> Session<'a> {
+    pub fn new(
+        ec: &'a dyn EcBackend,
+        register_map: &'a RegisterMap,
+        should_exit: &'a AtomicBool,
+    ) -> Session<'a> {
+        let (sender, lines) = mpsc::channel();
+        // Stdin is read on its own thread so that Ctrl-C is noticed while waiting for input.
+        thread::spawn(move || {
+            for line in io::stdin().lock().lines().map_while(Result::ok) {
+                if sender.send(line).is_err() {
+                    break;
+                }
+            }
+        });
+        Session::with_lines(ec, register_map, should_exit, lines)
+    }
+
+    fn with_lines(
+        ec: &'a dyn EcBackend,
+        register_map: &'a RegisterMap,
+        should_exit: &'a AtomicBool,
+        lines: Receiver<String>,
+    ) -> Session<'a> {
+        Session {
+            ec,
+            register_map,
+            should_exit,
+            lines,
+            originals: BTreeMap::new(),
+            undo: Vec::new(),
+        }
+    }
+
+    /// Runs until `quit`, end of input or Ctrl-C, then restores the touched registers.
+    pub fn run(&mut self) -> io::Result<()> {
+        println!("Type 'help' for commands. Touched registers are restored on exit.");
+        while let Some(line) = self.prompt()? {
+            let words: Vec<&str> = line.split_whitespace().collect();
+            if let ["quit"] | ["exit"] = words.as_slice() {
+                break;
+            }
+            if let Err(e) = self.execute(&words) {
+                println!("error: {}", e);
+            }
+        }
+        self.restore()
+    }
+
+    /// Runs one command other than `quit`.
+    fn execute(&mut self, words: &[&str]) -> Result<(), String> {
+        match words {
+            [] => Ok(()),
+            ["help"] => {
+                print!("{}", HELP);
+                Ok(())
+            }
+            ["read", register] => match self.register_map.get(register) {
+                Some(_) => self.read_named(register),
+                None => self.read(register, "1"),
+            },
+            ["read", register, count] => self.read(register, count),
+            ["write", register, value] => self.write(register, value),
+            ["bits", register] => self.show_bits(register),
+            ["bits", register, bits, value] => self.write_bits(register, bits, value),
+            ["watch", registers] => self.watch(registers, "500"),
+            ["watch", registers, interval] => self.watch(registers, interval),
+            ["undo"] => self.undo(),
+            ["touched"] => {
+                self.print_touched();
+                Ok(())
+            }
+            _ => Err(format!(
+                "unrecognised command '{}', try 'help'",
+                words.join(" ")
+            )),
+        }
+    }
+
+    fn prompt(&self) -> io::Result<Option<String>> {
+        print!("ec> ");
+        io::stdout().flush()?;
+        loop {
+            if self.should_exit.load(Ordering::Relaxed) {
+                println!();
+                return Ok(None);
+            }
+            match self.lines.recv_timeout(Duration::from_millis(100)) {
+                Ok(line) => return Ok(Some(line)),
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => {
+                    println!();
+                    return Ok(None);
+                }
+            }
+        }
+    }
+
+    fn read(&self, register: &str, count: &str) -> Result<(), String> {
+        let first = self.parse_register(register)?;
+        let count = parse_number(count)?;
+        if first
+            .checked_add(count)
+            .is_none_or(|end| end > EC_REGISTER_COUNT)
+        {
+            return Err(format!(
+                "{} registers from {:#04x} run past the end",
+                count, first
+            ));
+        }
+        for register_offset in first..first + count {
+            let value =
+                read_from_ec_register(self.ec, register_offset).map_err(|e| e.to_string())?;
+            println!("{}", self.describe(register_offset, value));
+        }
+        Ok(())
+    }
+
+    /// Shows every register a named field spans, then the field's own value.
+    fn read_named(&self, name: &str) -> Result<(), String> {
+        let def = self.register_map.get(name).ok_or("unknown register")?;
+        for register_offset in def.register_offsets() {
+            let value =
+                read_from_ec_register(self.ec, register_offset).map_err(|e| e.to_string())?;
+            println!("{}", self.describe(register_offset, value));
+        }
+        if !def.is_whole_register() {
+            let value = def.read(self.ec).map_err(|e| e.to_string())?;
+            println!("{} = {:#x} {}", name, value, value);
+        }
+        Ok(())
+    }
+
+    fn write(&mut self, register: &str, value: &str) -> Result<(), String> {
+        let register_offset = self.parse_writable_register(register)?;
+        let value = parse_byte(value)?;
+        self.write_recorded(register_offset, value)
+    }
+
+    fn show_bits(&self, register: &str) -> Result<(), String> {
+        let register_offset = self.parse_register(register)?;
+        let value = read_from_ec_register(self.ec, register_offset).map_err(|e| e.to_string())?;
+        println!("bit   7 6 5 4 3 2 1 0");
+        let bits: Vec<String> = (0..8)
+            .rev()
+            .map(|bit| (value >> bit & 1).to_string())
+            .collect();
+        println!("{:#04x}  {}", register_offset, bits.join(" "));
+        Ok(())
+    }
+
+    fn write_bits(&mut self, register: &str, bits: &str, value: &str) -> Result<(), String> {
+        let register_offset = self.parse_writable_register(register)?;
+        let (first_bit, width) = bits.split_once(':').unwrap_or((bits, "1"));
+        let (first_bit, width) = (parse_number(first_bit)?, parse_number(width)?);
+        if width == 0 || first_bit.checked_add(width).is_none_or(|end| end > 8) {
+            return Err(format!("bits {} do not fit in a register", bits));
+        }
+        let field = BitField::new(register_offset, first_bit as u8, width as u8);
+        let current = read_from_ec_register(self.ec, register_offset).map_err(|e| e.to_string())?;
+        let updated = field
+            .insert(current, parse_byte(value)?)
+            .map_err(|e| e.to_string())?;
+        self.write_recorded(register_offset, updated)?;
+        self.show_bits(register)
+    }
+
+    fn watch(&self, registers: &str, interval: &str) -> Result<(), String> {
+        let registers: Vec<u64> = parse_register_ranges(registers, self.register_map)?
+            .into_iter()
+            .flatten()
+            .collect();
+        let interval = Duration::from_millis(parse_number(interval)?);
+        println!("Watching, press Enter to stop.");
+        let mut last: BTreeMap<u64, u8> = BTreeMap::new();
+        while !self.should_exit.load(Ordering::Relaxed) {
+            for &register_offset in &registers {
+                let value =
+                    read_from_ec_register(self.ec, register_offset).map_err(|e| e.to_string())?;
+                if last.insert(register_offset, value) != Some(value) {
+                    println!("{}", self.describe(register_offset, value));
+                }
+            }
+            match self.lines.recv_timeout(interval) {
+                Err(RecvTimeoutError::Timeout) => {}
+                _ => break,
+            }
+        }
+        Ok(())
+    }
+
+    fn undo(&mut self) -> Result<(), String> {
+        let (register_offset, previous) = self.undo.pop().ok_or("nothing to undo")?;
+        write_to_ec_register(self.ec, register_offset, previous).map_err(|e| e.to_string())?;
+        println!("{} (restored)", self.describe(register_offset, previous));
+        Ok(())
+    }
+
+    fn write_recorded(&mut self, register_offset: u64, value: u8) -> Result<(), String> {
+        let previous =
+            read_from_ec_register(self.ec, register_offset).map_err(|e| e.to_string())?;
+        self.originals.entry(register_offset).or_insert(previous);
+        write_to_ec_register(self.ec, register_offset, value).map_err(|e| e.to_string())?;
+        self.undo.push((register_offset, previous));
+        let read_back =
+            read_from_ec_register(self.ec, register_offset).map_err(|e| e.to_string())?;
+        println!(
+            "{:#04x}: {:#04x} -> {:#04x}, reads back {:#04x}",
+            register_offset, previous, value, read_back
+        );
+        Ok(())
+    }
+
+    fn print_touched(&self) {
+        if self.originals.is_empty() {
+            println!("No registers written.");
+        }
+        for (&register_offset, &original) in &self.originals {
+            println!("{:#04x}: originally {:#04x}", register_offset, original);
+        }
+    }
+
+    fn describe(&self, register_offset: u64, value: u8) -> String {
+        let line = format!(
+            "{:#04x}: {:#04x} {:3} {:08b}  {}",
+            register_offset,
+            value,
+            value,
+            value,
+            self.register_map.label(register_offset)
+        );
+        line.trim_end().to_string()
+    }
+
+    fn parse_register(&self, s: &str) -> Result<u64, String> {
+        self.register_map
+            .resolve(s)
+            .map_err(|_| format!("'{}' is not a register", s))
+    }
+
+    /// Like `parse_register`, but refuses names the register map marks as read-only.
+    /// Raw offsets are always allowed, as the map may be wrong.
+    fn parse_writable_register(&self, s: &str) -> Result<u64, String> {
+        match self.register_map.get(s) {
+            Some(def) if !def.writable => Err(format!(
+                "{} is read-only in the register map, use its offset {:#04x} to write it anyway",
+                s, def.offset
+            )),
+            _ => self.parse_register(s),
+        }
+    }
+
+    fn restore(&mut self) -> io::Result<()> {
+        let mut result = Ok(());
+        for (&register_offset, &original) in &self.originals {
+            match write_to_ec_register(self.ec, register_offset, original) {
+                Ok(()) => println!("Restored {:#04x} to {:#04x}", register_offset, original),
+                Err(e) => {
+                    eprintln!(
+                        "Failed to restore {:#04x} to {:#04x}: {}",
+                        register_offset, original, e
+                    );
+                    result = Err(e);
+                }
+            }
+        }
+        self.originals.clear();
+        self.undo.clear();
+        result
+    }
+}
+
+impl Drop for Session<'_> {
+    fn drop(&mut self) {
+        // Normally a no-op, as run() restores on the way out; this covers early returns.
+        let _ = self.restore();
+    }
+}
+
+fn parse_number(s: &str) -> Result<u64, String> {
+    parse_register_number(s).map_err(|_| format!("'{}' is not a number", s))
+}
+
+fn parse_byte(s: &str) -> Result<u8, String> {
+    parse_number(s)
+        .ok()
+        .filter(|&v| v <= 0xff)
+        .map(|v| v as u8)
+        .ok_or_else(|| format!("'{}' is not a byte value", s))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ec::MemoryEc;
+
+    fn memory_ec(values: &[(u64, u8)]) -> MemoryEc {
+        let ec = MemoryEc::new();
+        for &(register_offset, value) in values {
+            ec.write_register(register_offset, value).unwrap();
+        }
+        ec
+    }
+
+    /// A session reading `lines` and then the end of input.
+    fn session<'a>(
+        ec: &'a MemoryEc,
+        register_map: &'a RegisterMap,
+        should_exit: &'a AtomicBool,
+        lines: &[&str],
+    ) -> Session<'a> {
+        let (sender, receiver) = mpsc::channel();
+        for line in lines {
+            sender.send(line.to_string()).unwrap();
+        }
+        Session::with_lines(ec, register_map, should_exit, receiver)
+    }
+
+    fn registers(ec: &MemoryEc, offsets: &[u64]) -> Vec<u8> {
+        offsets
+            .iter()
+            .map(|&r| ec.read_register(r).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn restores_touched_registers_on_quit() {
+        let ec = memory_ec(&[(0x40, 0x01), (0x41, 0xf0)]);
+        let map = RegisterMap::parse_lines("ReadRegister 0x58 CPUT 8").unwrap();
+        let should_exit = AtomicBool::new(false);
+        let mut session = session(
+            &ec,
+            &map,
+            &should_exit,
+            &[
+                "write 0x40 0x05",
+                "write 0x40 0x06",
+                "bits 0x41 0:4 0xa",
+                "write 0x42 0x00",
+                "write CPUT 0x30",
+                "write 0x43 0x100",
+                "quit",
+                "write 0x44 0x07",
+            ],
+        );
+        session.run().unwrap();
+        assert_eq!(
+            registers(&ec, &[0x40, 0x41, 0x42, 0x43, 0x44, 0x58]),
+            [0x01, 0xf0, 0x00, 0x00, 0x00, 0x00]
+        );
+        assert!(session.originals.is_empty());
+    }
+
+    #[test]
+    fn restores_touched_registers_at_the_end_of_input() {
+        let ec = memory_ec(&[(0x93, 0x04)]);
+        let map = RegisterMap::default();
+        let should_exit = AtomicBool::new(false);
+        session(&ec, &map, &should_exit, &["write 0x93 0x14"])
+            .run()
+            .unwrap();
+        assert_eq!(registers(&ec, &[0x93]), [0x04]);
+    }
+
+    #[test]
+    fn undoes_writes_one_at_a_time() {
+        let ec = memory_ec(&[(0x40, 0x01)]);
+        let map = RegisterMap::default();
+        let should_exit = AtomicBool::new(false);
+        let mut session = session(&ec, &map, &should_exit, &[]);
+        session.execute(&["write", "0x40", "5"]).unwrap();
+        session.execute(&["bits", "0x40", "4", "1"]).unwrap();
+        assert_eq!(registers(&ec, &[0x40]), [0x15]);
+        session.execute(&["undo"]).unwrap();
+        assert_eq!(registers(&ec, &[0x40]), [0x05]);
+        session.execute(&["undo"]).unwrap();
+        assert_eq!(registers(&ec, &[0x40]), [0x01]);
+        assert!(session.execute(&["undo"]).is_err());
+
+        // Whatever is left touched is put back when the session goes away early.
+        session.execute(&["write", "0x40", "9"]).unwrap();
+        session.execute(&["write", "0x41", "9"]).unwrap();
+        drop(session);
+        assert_eq!(registers(&ec, &[0x40, 0x41]), [0x01, 0x00]);
+    }
+}
@@ -0,0 +1,784 @@
+use std::fs;
+use std::future::Future;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+
+use derive_more::Display;
+use regex::Regex;
+
+use crate::ec::EcBackend;
+use crate::nvidia_smi::{NvidiaSmiGpu, SharedNvidiaSmi};
+use crate::profile::{Aggregate, EcRegisterProfile, HwmonProfile, SensorProfile};
+use crate::regmap::{RegisterDef, RegisterMap};
+
+pub const NVIDIA_SMI: &str = "nvidia-smi";
+pub const THERMAL_DIR: &str = "/sys/class/thermal";
+pub const HWMON_DIR: &str = "/sys/class/hwmon";
+
+#[derive(Display, Debug)]
+pub enum SensorError {
+    #[display(fmt = "{}", _0)]
+    Io(io::Error),
+    #[display(fmt = "unexpected reading '{}'", _0)]
+    Parse(String),
+    #[display(fmt = "{}", _0)]
+    Command(String),
+    #[display(fmt = "{}", _0)]
+    Invalid(String),
+}
+
+impl std::error::Error for SensorError {}
+
+impl From<io::Error> for SensorError {
+    fn from(e: io::Error) -> SensorError {
+        SensorError::Io(e)
+    }
+}
+
+/// What a source's readings are in.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Display)]
+pub enum Unit {
+    #[display(fmt = "°C")]
+    Celsius,
+    /// As in the kernel's thermal and hwmon interfaces.
+    #[display(fmt = "m°C")]
+    Millicelsius,
+}
+
+impl Unit {
+    pub fn to_celsius(self, value: f64) -> f64 {
+        match self {
+            Unit::Celsius => value,
+            Unit::Millicelsius => value / 1000.0,
+        }
+    }
+}
+
+pub type ReadFuture<'a> = Pin<Box<dyn Future<Output = Result<f64, SensorError>> + Send + 'a>>;
+
+pub type Sources<'a> = Vec<Box<dyn TemperatureSource + 'a>>;
+
+/// Somewhere a temperature can be read from. Sources are read once per controller cycle, so
+/// `read` should not hold up the runtime for long.
+pub trait TemperatureSource: Send + Sync {
+    /// How the source is shown in logs and errors.
+    fn name(&self) -> &str;
+
+    fn unit(&self) -> Unit;
+
+    /// A reading in `unit`.
+    fn read(&self) -> ReadFuture<'_>;
+}
+
+impl dyn TemperatureSource + '_ {
+    pub async fn read_celsius(&self) -> Result<f64, SensorError> {
+        Ok(self.unit().to_celsius(self.read().await?))
+    }
+}
+
+/// Reads a file holding a single number, such as a thermal zone's `temp`.
+async fn read_number_file(path: &str) -> Result<f64, SensorError> {
+    let text = tokio::fs::read_to_string(path)
+        .await
+        .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path, e)))?;
+    text.trim()
+        .parse()
+        .map_err(|_| SensorError::Parse(text.trim().to_string()))
+}
+
+/// A `temp` file under `/sys/class/thermal`.
+pub struct ThermalZone {
+    path: String,
+    name: String,
+}
+
+impl ThermalZone {
+    pub fn new(path: &str) -> ThermalZone {
+        ThermalZone {
+            path: path.to_string(),
+            name: path.to_string(),
+        }
+    }
+
+    pub fn from_info(zone: &ThermalZoneInfo) -> ThermalZone {
+        ThermalZone {
+            path: zone.temp_path(),
+            name: format!("{} ({})", zone.zone_type, zone.name),
+        }
+    }
+}
+
+impl TemperatureSource for ThermalZone {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn unit(&self) -> Unit {
+        Unit::Millicelsius
+    }
+
+    fn read(&self) -> ReadFuture<'_> {
+        Box::pin(read_number_file(&self.path))
+    }
+}
+
+/// A thermal zone as the kernel lists it.
+#[derive(Clone, Debug)]
+pub struct ThermalZoneInfo {
+    /// Such as `thermal_zone8`; the number depends on probe order.
+    pub name: String,
+    /// What the zone measures, such as `x86_pkg_temp` or `acpitz`.
+    pub zone_type: String,
+    pub dir: PathBuf,
+}
+
+impl ThermalZoneInfo {
+    pub fn temp_path(&self) -> String {
+        self.dir.join("temp").display().to_string()
+    }
+}
+
+/// Every thermal zone under `dir`, in order of zone number. A missing `dir` has none.
+pub fn thermal_zones<P: AsRef<Path>>(dir: P) -> io::Result<Vec<ThermalZoneInfo>> {
+    let mut zones = numbered_entries(dir, "thermal_zone")?;
+    zones.sort_by_key(|(n, _, _)| *n);
+    Ok(zones
+        .into_iter()
+        .map(|(_, name, dir)| {
+            let zone_type = fs::read_to_string(dir.join("type")).unwrap_or_default();
+            ThermalZoneInfo {
+                name,
+                zone_type: zone_type.trim().to_string(),
+                dir,
+            }
+        })
+        .collect())
+}
+
+/// The first zone of the first type in `types` that is present, so that a profile keeps
+/// reading the same sensor when zones are numbered differently.
+pub fn find_thermal_zone(dir: &str, types: &[String]) -> Result<ThermalZone, SensorError> {
+    let zones = thermal_zones(dir)?;
+    types
+        .iter()
+        .find_map(|zone_type| zones.iter().find(|zone| &zone.zone_type == zone_type))
+        .map(ThermalZone::from_info)
+        .ok_or_else(|| {
+            SensorError::Invalid(format!(
+                "no thermal zone of type {} in {}",
+                types.join(", "),
+                dir
+            ))
+        })
+}
+
+/// A temperature channel of a hwmon device, as the kernel lists it.
+#[derive(Clone, Debug)]
+pub struct HwmonChannel {
+    /// Such as `hwmon3`; the number depends on probe order.
+    pub device: String,
+    /// The device's `name`.
+    pub driver: String,
+    /// The channel's `temp*_label`, or its file name, such as `temp1`, when it has none.
+    pub label: String,
+    pub input: PathBuf,
+}
+
+/// Every temperature channel under `dir`, in order of device and channel number. A missing
+/// `dir` has none.
+pub fn hwmon_channels<P: AsRef<Path>>(dir: P) -> io::Result<Vec<HwmonChannel>> {
+    let mut devices = numbered_entries(dir, "hwmon")?;
+    devices.sort_by_key(|(n, _, _)| *n);
+    let mut channels = Vec::new();
+    for (_, device, device_dir) in devices {
+        let driver = fs::read_to_string(device_dir.join("name")).unwrap_or_default();
+        let mut inputs: Vec<(u64, String)> = fs::read_dir(&device_dir)?
+            .filter_map(|entry| {
+                let file = entry.ok()?.file_name().to_string_lossy().to_string();
+                let channel = file.strip_prefix("temp")?.strip_suffix("_input")?;
+                Some((channel.parse().ok()?, file))
+            })
+            .collect();
+        inputs.sort();
+        for (channel, input) in inputs {
+            let label = fs::read_to_string(device_dir.join(format!("temp{}_label", channel)))
+                .map(|label| label.trim().to_string())
+                .unwrap_or_else(|_| format!("temp{}", channel));
+            channels.push(HwmonChannel {
+                device: device.clone(),
+                driver: driver.trim().to_string(),
+                label,
+                input: device_dir.join(input),
+            });
+        }
+    }
+    Ok(channels)
+}
+
+/// Entries of `dir` named `prefix` and a number, as `(number, name, path)`.
+fn numbered_entries<P: AsRef<Path>>(
+    dir: P,
+    prefix: &str,
+) -> io::Result<Vec<(u64, String, PathBuf)>> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+    let mut numbered = Vec::new();
+    for entry in entries {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().to_string();
+        if let Some(Ok(n)) = name.strip_prefix(prefix).map(str::parse::<u64>) {
+            numbered.push((n, name, entry.path()));
+        }
+    }
+    Ok(numbered)
+}
+
+/// The matching channels of a hwmon device, combined into one reading.
+pub struct Hwmon {
+    name: String,
+    inputs: Vec<String>,
+    aggregate: Aggregate,
+}
+
+impl Hwmon {
+    /// The channels under `dir` that `profile` picks.
+    pub fn find(dir: &str, profile: &HwmonProfile) -> Result<Hwmon, SensorError> {
+        let label = profile
+            .label
+            .as_deref()
+            .map(Regex::new)
+            .transpose()
+            .map_err(|e| SensorError::Invalid(e.to_string()))?;
+        let inputs: Vec<String> = hwmon_channels(dir)?
+            .into_iter()
+            .filter(|c| c.driver == profile.driver)
+            .filter(|c| label.as_ref().is_none_or(|label| label.is_match(&c.label)))
+            .map(|c| c.input.display().to_string())
+            .collect();
+        let label = profile.label.as_deref().unwrap_or("*");
+        if inputs.is_empty() {
+            return Err(SensorError::Invalid(format!(
+                "no {} hwmon channel labelled {} in {}",
+                profile.driver, label, dir
+            )));
+        }
+        Ok(Hwmon {
+            name: format!(
+                "{} {} ({} of {})",
+                profile.driver,
+                label,
+                profile.aggregate,
+                inputs.len()
+            ),
+            inputs,
+            aggregate: profile.aggregate,
+        })
+    }
+
+    /// A single channel, as listed by `sensors`.
+    pub fn channel(channel: &HwmonChannel) -> Hwmon {
+        Hwmon {
+            name: format!("{} {}", channel.driver, channel.label),
+            inputs: vec![channel.input.display().to_string()],
+            aggregate: Aggregate::Max,
+        }
+    }
+
+    /// Combines the channels that can be read, failing only when none can.
+    async fn read_channels(&self) -> Result<f64, SensorError> {
+        let mut readings = Vec::new();
+        let mut first_error = None;
+        for input in &self.inputs {
+            match read_number_file(input).await {
+                Ok(reading) => readings.push(reading),
+                Err(e) => {
+                    first_error.get_or_insert(e);
+                }
+            }
+        }
+        match self.aggregate.apply(&readings) {
+            Some(reading) => Ok(reading),
+            None => Err(first_error.unwrap()),
+        }
+    }
+}
+
+impl TemperatureSource for Hwmon {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn unit(&self) -> Unit {
+        Unit::Millicelsius
+    }
+
+    fn read(&self) -> ReadFuture<'_> {
+        Box::pin(self.read_channels())
+    }
+}
+
+/// A register, or named field, holding the EC's own reading, taken as `raw * scale + offset`
+/// degrees.
+pub struct EcRegister<'a> {
+    ec: &'a dyn EcBackend,
+    name: String,
+    def: RegisterDef,
+    scale: f64,
+    offset: f64,
+}
+
+impl<'a> EcRegister<'a> {
+    /// `profile.register` is a name from `register_map` or a register number.
+    pub fn new(
+        ec: &'a dyn EcBackend,
+        profile: &EcRegisterProfile,
+        register_map: &RegisterMap,
+    ) -> Result<EcRegister<'a>, SensorError> {
+        let register = &profile.register;
+        let def = match register_map.get(register) {
+            Some(def) => def.clone(),
+            None => RegisterDef::register(
+                register_map
+                    .resolve(register)
+                    .map_err(|e| SensorError::Invalid(e.to_string()))?,
+            ),
+        };
+        Ok(EcRegister {
+            ec,
+            name: format!("EC {}", register),
+            def,
+            scale: profile.scale,
+            offset: profile.offset,
+        })
+    }
+}
+
+impl TemperatureSource for EcRegister<'_> {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn unit(&self) -> Unit {
+        Unit::Celsius
+    }
+
+    fn read(&self) -> ReadFuture<'_> {
+        Box::pin(async move {
+            // EC backends block, the port backend for as long as the EC takes to answer, so
+            // other tasks move to another worker meanwhile.
+            let raw = tokio::task::block_in_place(|| self.def.read(self.ec))?;
+            Ok(raw as f64 * self.scale + self.offset)
+        })
+    }
+}
+
+/// What opening a profile's sensors may need.
+pub struct SensorContext<'a> {
+    /// Only `ec_register` sensors need the EC.
+    pub ec: Option<&'a dyn EcBackend>,
+    pub register_map: &'a RegisterMap,
+    pub thermal_dir: &'a str,
+    pub hwmon_dir: &'a str,
+    /// Only started if an `nvidia_smi` sensor asks for it.
+    pub nvidia_smi: &'a SharedNvidiaSmi,
+}
+
+/// The source a profile's sensor entry describes.
+pub fn open<'a>(
+    sensor: &SensorProfile,
+    context: &SensorContext<'a>,
+) -> Result<Box<dyn TemperatureSource + 'a>, SensorError> {
+    Ok(match sensor {
+        SensorProfile::ThermalZone(path) => Box::new(ThermalZone::new(path)),
+        SensorProfile::ThermalZoneType(types) => {
+            Box::new(find_thermal_zone(context.thermal_dir, types)?)
+        }
+        SensorProfile::Hwmon(hwmon) => Box::new(Hwmon::find(context.hwmon_dir, hwmon)?),
+        SensorProfile::NvidiaSmi => Box::new(NvidiaSmiGpu::new(context.nvidia_smi.get(), 0)),
+        SensorProfile::NvidiaSmiGpu(gpu) => {
+            Box::new(NvidiaSmiGpu::new(context.nvidia_smi.get(), *gpu))
+        }
+        SensorProfile::EcRegister(profile) => {
+            let ec = context
+                .ec
+                .ok_or_else(|| SensorError::Invalid(format!("{} needs the EC", sensor)))?;
+            Box::new(EcRegister::new(ec, profile, context.register_map)?)
+        }
+    })
+}
+
+/// The sources that open, and the entries that do not with the reason.
+pub fn open_available<'a, 's>(
+    sensors: &'s [SensorProfile],
+    context: &SensorContext<'a>,
+) -> (Sources<'a>, Vec<(&'s SensorProfile, SensorError)>) {
+    let (mut sources, mut failed) = (Vec::new(), Vec::new());
+    for sensor in sensors {
+        match open(sensor, context) {
+            Ok(source) => sources.push(source),
+            Err(e) => failed.push((sensor, e)),
+        }
+    }
+    (sources, failed)
+}
+
+/// A reading for display, or the reason there is none.
+pub async fn describe_reading(source: &dyn TemperatureSource) -> String {
+    match source.read_celsius().await {
+        Ok(t) => format!("{:.1}°C", t),
+        Err(e) => format!("error: {}", e),
+    }
+}
+
+#[cfg(test)]
+pub mod mock {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{Arc, Mutex};
+
+    use super::{ReadFuture, SensorError, TemperatureSource, Unit};
+
+    /// A source that reads whatever it was last set to, sharing its state between clones so that
+    /// tests can change a reading after handing the source over.
+    #[derive(Clone)]
+    pub struct MockSource {
+        name: String,
+        unit: Unit,
+        reading: Arc<Mutex<Result<f64, String>>>,
+        reads: Arc<AtomicUsize>,
+    }
+
+    impl MockSource {
+        pub fn new(name: &str, reading: Result<f64, &str>) -> MockSource {
+            MockSource {
+                name: name.to_string(),
+                unit: Unit::Celsius,
+                reading: Arc::new(Mutex::new(reading.map_err(str::to_string))),
+                reads: Default::default(),
+            }
+        }
+
+        pub fn with_unit(mut self, unit: Unit) -> MockSource {
+            self.unit = unit;
+            self
+        }
+
+        pub fn set(&self, reading: Result<f64, &str>) {
+            *self.reading.lock().unwrap() = reading.map_err(str::to_string);
+        }
+
+        /// How many times the source has been read.
+        pub fn reads(&self) -> usize {
+            self.reads.load(Ordering::SeqCst)
+        }
+    }
+
+    impl TemperatureSource for MockSource {
+        fn name(&self) -> &str {
+            &self.name
+        }
+
+        fn unit(&self) -> Unit {
+            self.unit
+        }
+
+        fn read(&self) -> ReadFuture<'_> {
+            self.reads.fetch_add(1, Ordering::SeqCst);
+            let reading = self.reading.lock().unwrap().clone();
+            Box::pin(async move { reading.map_err(SensorError::Command) })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::mock::MockSource;
+    use super::*;
+
+    #[tokio::test]
+    async fn converts_readings_to_celsius() {
+        let source = MockSource::new("zone", Ok(48500.0)).with_unit(Unit::Millicelsius);
+        let source: &dyn TemperatureSource = &source;
+        assert_eq!(source.read_celsius().await.unwrap(), 48.5);
+        assert_eq!(describe_reading(source).await, "48.5°C");
+    }
+
+    #[tokio::test]
+    async fn describes_errors() {
+        let source = MockSource::new("zone", Err("gone"));
+        assert_eq!(describe_reading(&source).await, "error: gone");
+        assert_eq!(source.reads(), 1);
+    }
+
+    /// Writes `contents` to `path` under `dir`, making the directories on the way.
+    fn write(dir: &Path, path: &str, contents: &str) {
+        let path = dir.join(path);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, contents).unwrap();
+    }
+
+    /// Thermal zones numbered `n` with a type and a temperature in degrees.
+    fn thermal_dir(zones: &[(u32, &str, f64)]) -> tempfile::TempDir {
+        let dir = tempfile::tempdir().unwrap();
+        for &(n, zone_type, temperature) in zones {
+            let zone = format!("thermal_zone{}", n);
+            write(
+                dir.path(),
+                &format!("{}/type", zone),
+                &format!("{}\n", zone_type),
+            );
+            write(
+                dir.path(),
+                &format!("{}/temp", zone),
+                &format!("{}\n", temperature * 1000.0),
+            );
+        }
+        write(dir.path(), "cooling_device0/type", "Processor\n");
+        dir
+    }
+
+    fn types(types: &[&str]) -> Vec<String> {
+        types.iter().map(|t| t.to_string()).collect()
+    }
+
+    async fn read(source: &dyn TemperatureSource) -> f64 {
+        source.read_celsius().await.unwrap()
+    }
+
+    #[test]
+    fn lists_thermal_zones_in_number_order() {
+        let dir = thermal_dir(&[
+            (10, "TCPU", 50.0),
+            (2, "x86_pkg_temp", 60.0),
+            (0, "acpitz", 40.0),
+        ]);
+        let zones = thermal_zones(dir.path()).unwrap();
+        let listed: Vec<(&str, &str)> = zones
+            .iter()
+            .map(|z| (z.name.as_str(), z.zone_type.as_str()))
+            .collect();
+        assert_eq!(
+            listed,
+            [
+                ("thermal_zone0", "acpitz"),
+                ("thermal_zone2", "x86_pkg_temp"),
+                ("thermal_zone10", "TCPU")
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn finds_thermal_zones_by_type_whatever_their_number() {
+        let wanted = types(&["x86_pkg_temp"]);
+        for n in [1, 7] {
+            let dir = thermal_dir(&[(0, "acpitz", 40.0), (n, "x86_pkg_temp", 62.0)]);
+            let zone = find_thermal_zone(dir.path().to_str().unwrap(), &wanted).unwrap();
+            assert_eq!(zone.name(), format!("x86_pkg_temp (thermal_zone{})", n));
+            assert_eq!(read(&zone).await, 62.0);
+        }
+    }
+
+    #[tokio::test]
+    async fn prefers_earlier_types_and_lower_numbers() {
+        let dir = thermal_dir(&[
+            (0, "acpitz", 40.0),
+            (1, "acpitz", 45.0),
+            (5, "TCPU", 55.0),
+            (9, "x86_pkg_temp", 60.0),
+        ]);
+        let dir = dir.path().to_str().unwrap();
+        let zone = find_thermal_zone(dir, &types(&["x86_pkg_temp", "TCPU", "acpitz"])).unwrap();
+        assert_eq!(read(&zone).await, 60.0);
+        let zone = find_thermal_zone(dir, &types(&["B0D4", "TCPU", "acpitz"])).unwrap();
+        assert_eq!(read(&zone).await, 55.0);
+        let zone = find_thermal_zone(dir, &types(&["acpitz", "TCPU"])).unwrap();
+        assert_eq!(zone.name(), "acpitz (thermal_zone0)");
+        assert!(matches!(
+            find_thermal_zone(dir, &types(&["iwlwifi_1"])),
+            Err(SensorError::Invalid(_))
+        ));
+    }
+
+    /// nvme with a labelled channel, acpitz without labels, and coretemp with a package and
+    /// three cores, numbered so that file name order is not channel order.
+    fn hwmon_dir() -> tempfile::TempDir {
+        let dir = tempfile::tempdir().unwrap();
+        let d = dir.path();
+        write(d, "hwmon0/name", "nvme\n");
+        write(d, "hwmon0/temp1_input", "38850\n");
+        write(d, "hwmon0/temp1_label", "Composite\n");
+        write(d, "hwmon1/name", "acpitz\n");
+        write(d, "hwmon1/temp1_input", "45000\n");
+        write(d, "hwmon1/temp1_crit", "105000\n");
+        write(d, "hwmon3/name", "coretemp\n");
+        for (channel, label, temperature) in [
+            (1, "Package id 0", 66000),
+            (2, "Core 0", 52000),
+            (3, "Core 1", 53000),
+            (10, "Core 8", 60000),
+        ] {
+            write(
+                d,
+                &format!("hwmon3/temp{}_input", channel),
+                &format!("{}\n", temperature),
+            );
+            write(
+                d,
+                &format!("hwmon3/temp{}_label", channel),
+                &format!("{}\n", label),
+            );
+        }
+        dir
+    }
+
+    fn hwmon(driver: &str, label: Option<&str>, aggregate: Aggregate) -> HwmonProfile {
+        HwmonProfile {
+            driver: driver.to_string(),
+            label: label.map(str::to_string),
+            aggregate,
+        }
+    }
+
+    #[test]
+    fn lists_hwmon_channels_with_their_labels() {
+        let dir = hwmon_dir();
+        let channels: Vec<(String, String, String)> = hwmon_channels(dir.path())
+            .unwrap()
+            .into_iter()
+            .map(|c| (c.device, c.driver, c.label))
+            .collect();
+        let expected = [
+            ("hwmon0", "nvme", "Composite"),
+            ("hwmon1", "acpitz", "temp1"),
+            ("hwmon3", "coretemp", "Package id 0"),
+            ("hwmon3", "coretemp", "Core 0"),
+            ("hwmon3", "coretemp", "Core 1"),
+            ("hwmon3", "coretemp", "Core 8"),
+        ];
+        let expected: Vec<(String, String, String)> = expected
+            .iter()
+            .map(|&(d, n, l)| (d.to_string(), n.to_string(), l.to_string()))
+            .collect();
+        assert_eq!(channels, expected);
+        assert!(hwmon_channels(dir.path().join("missing"))
+            .unwrap()
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn picks_hwmon_channels_by_driver_and_label() {
+        let dir = hwmon_dir();
+        let dir = dir.path().to_str().unwrap();
+        let cores =
+            Hwmon::find(dir, &hwmon("coretemp", Some(r"^Core \d+$"), Aggregate::Max)).unwrap();
+        assert_eq!(cores.name(), r"coretemp ^Core \d+$ (max of 3)");
+        assert_eq!(read(&cores).await, 60.0);
+        let cores = Hwmon::find(dir, &hwmon("coretemp", Some("^Core"), Aggregate::Avg)).unwrap();
+        assert_eq!(read(&cores).await, 55.0);
+        let all = Hwmon::find(dir, &hwmon("coretemp", None, Aggregate::Max)).unwrap();
+        assert_eq!(all.name(), "coretemp * (max of 4)");
+        assert_eq!(read(&all).await, 66.0);
+        let unlabelled =
+            Hwmon::find(dir, &hwmon("acpitz", Some("^temp1$"), Aggregate::Max)).unwrap();
+        assert_eq!(read(&unlabelled).await, 45.0);
+
+        for profile in [
+            hwmon("k10temp", None, Aggregate::Max),
+            hwmon("nvme", Some("Sensor"), Aggregate::Max),
+            hwmon("coretemp", Some("(Core"), Aggregate::Max),
+        ] {
+            assert!(matches!(
+                Hwmon::find(dir, &profile),
+                Err(SensorError::Invalid(_))
+            ));
+        }
+    }
+
+    #[tokio::test]
+    async fn leaves_out_hwmon_channels_that_cannot_be_read() {
+        let dir = hwmon_dir();
+        let cores = Hwmon::find(
+            dir.path().to_str().unwrap(),
+            &hwmon("coretemp", Some("^Core"), Aggregate::Avg),
+        )
+        .unwrap();
+        fs::remove_file(dir.path().join("hwmon3/temp10_input")).unwrap();
+        assert_eq!(read(&cores).await, 52.5);
+        fs::write(dir.path().join("hwmon3/temp3_input"), "busy\n").unwrap();
+        assert_eq!(read(&cores).await, 52.0);
+        fs::remove_file(dir.path().join("hwmon3/temp2_input")).unwrap();
+        let source: &dyn TemperatureSource = &cores;
+        assert!(source.read_celsius().await.is_err());
+    }
+
+    #[test]
+    fn a_missing_thermal_dir_has_no_zones() {
+        let dir = tempfile::tempdir().unwrap();
+        let missing = dir.path().join("thermal");
+        assert!(thermal_zones(&missing).unwrap().is_empty());
+        assert!(matches!(
+            find_thermal_zone(missing.to_str().unwrap(), &types(&["acpitz"])),
+            Err(SensorError::Invalid(_))
+        ));
+    }
+
+    fn ec_register(register: &str, scale: f64, offset: f64) -> EcRegisterProfile {
+        EcRegisterProfile {
+            register: register.to_string(),
+            scale,
+            offset,
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn scales_ec_registers_by_name_or_number() {
+        let mut registers = [0u8; crate::ec::EC_REGISTER_COUNT as usize];
+        registers[0x58] = 100;
+        registers[0x60] = 0xa5;
+        let ec = crate::ec::MemoryEc::with_registers(registers);
+        let map =
+            RegisterMap::parse_lines("ReadRegister 0x58 CPUT 8\nReadRegister 0x60 LOW 4, HIGH 4")
+                .unwrap();
+
+        let raw = EcRegister::new(&ec, &ec_register("0x58", 0.5, 10.0), &map).unwrap();
+        assert_eq!(raw.name(), "EC 0x58");
+        assert_eq!(read(&raw).await, 60.0);
+        let named = EcRegister::new(&ec, &ec_register("CPUT", 1.0, 0.0), &map).unwrap();
+        assert_eq!(read(&named).await, 100.0);
+        let field = EcRegister::new(&ec, &ec_register("HIGH", 2.0, -5.0), &map).unwrap();
+        assert_eq!(read(&field).await, 15.0);
+
+        for register in ["CPU_TEMP", "0x100"] {
+            assert!(matches!(
+                EcRegister::new(&ec, &ec_register(register, 1.0, 0.0), &map),
+                Err(SensorError::Invalid(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn opens_the_sensors_it_can() {
+        let map = RegisterMap::default();
+        let nvidia_smi = SharedNvidiaSmi::new(NVIDIA_SMI);
+        let context = SensorContext {
+            ec: None,
+            register_map: &map,
+            thermal_dir: "/nonexistent",
+            hwmon_dir: "/nonexistent",
+            nvidia_smi: &nvidia_smi,
+        };
+        let sensors = [
+            SensorProfile::ThermalZoneType(vec!["x86_pkg_temp".to_string()]),
+            SensorProfile::ThermalZone("/nonexistent/thermal_zone0/temp".to_string()),
+            SensorProfile::EcRegister(ec_register("0x58", 1.0, 0.0)),
+        ];
+        let (sources, failed) = open_available(&sensors, &context);
+        let names: Vec<&str> = sources.iter().map(|s| s.name()).collect();
+        assert_eq!(names, ["/nonexistent/thermal_zone0/temp"]);
+        let failed: Vec<&SensorProfile> = failed.into_iter().map(|(sensor, _)| sensor).collect();
+        assert_eq!(failed, [&sensors[0], &sensors[2]]);
+    }
+}
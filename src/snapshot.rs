@@ -0,0 +1,144 @@
+use std::convert::TryFrom;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use crate::ec::EC_REGISTER_COUNT;
+use crate::regmap::RegisterMap;
+
+pub type Registers = [u8; EC_REGISTER_COUNT as usize];
+
+/// Saves the registers as a raw 256 byte image, which can also be opened with `--ec file:`.
+pub fn save<P: AsRef<Path>>(path: P, registers: &Registers) -> io::Result<()> {
+    fs::write(path, registers)
+}
+
+pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Registers> {
+    let path = path.as_ref();
+    let bytes = fs::read(path)?;
+    let len = bytes.len();
+    Registers::try_from(bytes).map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "{} is {} bytes, an EC snapshot is {}",
+                path.display(),
+                len,
+                EC_REGISTER_COUNT
+            ),
+        )
+    })
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RegisterDiff {
+    pub register_offset: u64,
+    pub before: u8,
+    pub after: u8,
+}
+
+impl RegisterDiff {
+    pub fn changed_bits(&self) -> u8 {
+        self.before ^ self.after
+    }
+}
+
+pub fn diff(before: &Registers, after: &Registers) -> Vec<RegisterDiff> {
+    (0..EC_REGISTER_COUNT)
+        .filter(|&r| before[r as usize] != after[r as usize])
+        .map(|r| RegisterDiff {
+            register_offset: r,
+            before: before[r as usize],
+            after: after[r as usize],
+        })
+        .collect()
+}
+
+/// One line per changed register with both values in hex and binary, a `^` under each
+/// changed bit, and the names covering that register.
+pub fn render_diff(diffs: &[RegisterDiff], register_map: &RegisterMap) -> String {
+    let mut out = String::from("register  before         after          bits      name\n");
+    for d in diffs {
+        let markers: String = (0..8)
+            .rev()
+            .map(|bit| {
+                if d.changed_bits() & 1 << bit != 0 {
+                    '^'
+                } else {
+                    '.'
+                }
+            })
+            .collect();
+        let line = format!(
+            "0x{:02X}      {:02X} {:08b}    {:02X} {:08b}    {}  {}",
+            d.register_offset,
+            d.before,
+            d.before,
+            d.after,
+            d.after,
+            markers,
+            register_map.label(d.register_offset)
+        );
+        out.push_str(line.trim_end());
+        out.push('\n');
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn registers(values: &[(usize, u8)]) -> Registers {
+        let mut registers = [0u8; EC_REGISTER_COUNT as usize];
+        for &(offset, value) in values {
+            registers[offset] = value;
+        }
+        registers
+    }
+
+    #[test]
+    fn saves_and_loads_raw_images() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("ec.bin");
+        let mut saved = registers(&[]);
+        for (i, value) in saved.iter_mut().enumerate() {
+            *value = i as u8;
+        }
+        save(&path, &saved).unwrap();
+        assert_eq!(fs::read(&path).unwrap().len(), 256);
+        assert_eq!(load(&path).unwrap(), saved);
+
+        fs::write(&path, [0u8; 255]).unwrap();
+        let error = load(&path).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        assert!(error
+            .to_string()
+            .ends_with("is 255 bytes, an EC snapshot is 256"));
+        assert!(load(dir.path().join("missing")).is_err());
+    }
+
+    #[test]
+    fn shows_which_bits_changed() {
+        let before = registers(&[(0x40, 0b0000_0001), (0x58, 0x2d), (0xb2, 0xff)]);
+        let after = registers(&[(0x40, 0b0000_1000), (0x58, 0x2d), (0xb3, 0x01)]);
+        let diffs = diff(&before, &after);
+        let changed: Vec<(u64, u8)> = diffs
+            .iter()
+            .map(|d| (d.register_offset, d.changed_bits()))
+            .collect();
+        assert_eq!(changed, [(0x40, 0b0000_1001), (0xb2, 0xff), (0xb3, 0x01)]);
+        assert!(diff(&before, &before).is_empty());
+
+        let map =
+            RegisterMap::parse_lines("Register 0x40 SW2S 1, 2, ACCC 1\nReadRegister 0xb2 RPM1 8")
+                .unwrap();
+        assert_eq!(
+            render_diff(&diffs, &map),
+            "register  before         after          bits      name\n\
+             0x40      01 00000001    08 00001000    ....^..^  ACCC, SW2S\n\
+             0xB2      FF 11111111    00 00000000    ^^^^^^^^  RPM1\n\
+             0xB3      00 00000000    01 00000001    .......^\n"
+        );
+    }
+}
@@ -0,0 +1,331 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Write;
+use std::ops::RangeInclusive;
+use std::str::FromStr;
+use std::time::Duration;
+
+use crate::ec::EC_REGISTER_COUNT;
+use crate::regmap::RegisterMap;
+
+/// Which registers to watch, e.g. `0x40-0x60,CPU_FAN_RPM` minus an ignore list.
+#[derive(Clone, Debug)]
+pub struct RegisterFilter {
+    ranges: Vec<RangeInclusive<u64>>,
+    ignored: BTreeSet<u64>,
+}
+
+impl Default for RegisterFilter {
+    fn default() -> RegisterFilter {
+        RegisterFilter {
+            ranges: vec![0..=EC_REGISTER_COUNT - 1],
+            ignored: BTreeSet::new(),
+        }
+    }
+}
+
+impl RegisterFilter {
+    pub fn new(
+        ranges: Option<&str>,
+        ignored: Option<&str>,
+        register_map: &RegisterMap,
+    ) -> Result<RegisterFilter, String> {
+        let mut filter = RegisterFilter::default();
+        if let Some(ranges) = ranges {
+            filter.ranges = parse_register_ranges(ranges, register_map)?;
+        }
+        if let Some(ignored) = ignored {
+            filter.ignored = parse_register_ranges(ignored, register_map)?
+                .into_iter()
+                .flatten()
+                .collect();
+        }
+        Ok(filter)
+    }
+
+    pub fn includes(&self, register_offset: u64) -> bool {
+        !self.ignored.contains(&register_offset)
+            && self.ranges.iter().any(|r| r.contains(&register_offset))
+    }
+}
+
+/// Parses a comma separated list of registers and inclusive ranges such as `0x40-0x4f,0xb2`.
+/// Registers can also be named, and a bare name covers every register the name spans.
+pub fn parse_register_ranges(
+    s: &str,
+    register_map: &RegisterMap,
+) -> Result<Vec<RangeInclusive<u64>>, String> {
+    s.split(',')
+        .map(str::trim)
+        .filter(|part| !part.is_empty())
+        .map(|part| {
+            if let Some(def) = register_map.get(part) {
+                let offsets = def.register_offsets();
+                return Ok(offsets.start..=offsets.end - 1);
+            }
+            let (first, last) = part.split_once('-').unwrap_or((part, part));
+            let resolve = |r: &str| register_map.resolve(r.trim()).map_err(|e| e.to_string());
+            let (first, last) = (resolve(first)?, resolve(last)?);
+            if first > last {
+                return Err(format!("invalid register range '{}'", part));
+            }
+            Ok(first..=last)
+        })
+        .collect()
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WatchFormat {
+    /// The column aligned change timeline used in the Readme, printed when watching stops.
+    Timeline,
+    /// One JSON object per change, streamed as they happen.
+    Json,
+    /// One `elapsed_ms,register,old,new,name` line per change, streamed as they happen.
+    Csv,
+}
+
+impl FromStr for WatchFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "timeline" => Ok(WatchFormat::Timeline),
+            "json" => Ok(WatchFormat::Json),
+            "csv" => Ok(WatchFormat::Csv),
+            _ => Err(format!(
+                "unknown watch format '{}', expected timeline, json or csv",
+                s
+            )),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RegisterChange {
+    pub elapsed: Duration,
+    pub register_offset: u64,
+    pub old: u8,
+    pub new: u8,
+}
+
+impl RegisterChange {
+    /// `name` is the register's label from the register map, left out when empty.
+    pub fn to_json(self, name: &str) -> String {
+        let mut json = format!(
+            "{{\"elapsed_ms\":{},\"register\":{},\"old\":{},\"new\":{}",
+            self.elapsed.as_millis(),
+            self.register_offset,
+            self.old,
+            self.new
+        );
+        if !name.is_empty() {
+            write!(json, ",\"name\":{}", json_string(name)).unwrap();
+        }
+        json.push('}');
+        json
+    }
+
+    pub fn to_csv(self, name: &str) -> String {
+        format!(
+            "{},{:#04x},{:#04x},{:#04x},{}",
+            self.elapsed.as_millis(),
+            self.register_offset,
+            self.old,
+            self.new,
+            csv_field(name)
+        )
+    }
+}
+
+/// `s` as a JSON string literal.
+fn json_string(s: &str) -> String {
+    let mut json = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            c if (c as u32) < 0x20 => write!(json, "\\u{:04x}", c as u32).unwrap(),
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+    json
+}
+
+/// `s` as a CSV field, quoted when it holds a separator, quote or line break. Labels of
+/// registers with several names hold commas.
+fn csv_field(s: &str) -> String {
+    if s.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s.to_string()
+    }
+}
+
+pub const CSV_HEADER: &str = "elapsed_ms,register,old,new,name";
+
+/// Every poll that changed at least one watched register, relative to the first poll.
+pub struct Timeline {
+    filter: RegisterFilter,
+    initial: [u8; EC_REGISTER_COUNT as usize],
+    last: [u8; EC_REGISTER_COUNT as usize],
+    samples: Vec<BTreeMap<u64, u8>>,
+}
+
+impl Timeline {
+    pub fn new(filter: RegisterFilter, initial: [u8; EC_REGISTER_COUNT as usize]) -> Timeline {
+        Timeline {
+            filter,
+            initial,
+            last: initial,
+            samples: Vec::new(),
+        }
+    }
+
+    /// Records a poll and returns the watched registers that changed since the previous one.
+    pub fn record(
+        &mut self,
+        elapsed: Duration,
+        registers: &[u8; EC_REGISTER_COUNT as usize],
+    ) -> Vec<RegisterChange> {
+        let changes: Vec<RegisterChange> = (0..EC_REGISTER_COUNT)
+            .filter(|&r| self.filter.includes(r))
+            .filter(|&r| registers[r as usize] != self.last[r as usize])
+            .map(|r| RegisterChange {
+                elapsed,
+                register_offset: r,
+                old: self.last[r as usize],
+                new: registers[r as usize],
+            })
+            .collect();
+        if !changes.is_empty() {
+            self.samples
+                .push(changes.iter().map(|c| (c.register_offset, c.new)).collect());
+        }
+        self.last = *registers;
+        changes
+    }
+
+    pub fn change_counts(&self) -> BTreeMap<u64, usize> {
+        let mut counts = BTreeMap::new();
+        for sample in &self.samples {
+            for &register_offset in sample.keys() {
+                *counts.entry(register_offset).or_insert(0) += 1;
+            }
+        }
+        counts
+    }
+
+    /// Renders one row per register that changed, leaving out registers that changed more
+    /// than `max_changes` times. Each column is a poll in which something changed, and the
+    /// register's names follow the last column.
+    pub fn render(&self, max_changes: Option<usize>, register_map: &RegisterMap) -> String {
+        let rows: Vec<u64> = self
+            .change_counts()
+            .into_iter()
+            .filter(|&(_, count)| max_changes.is_none_or(|max| count <= max))
+            .map(|(register_offset, _)| register_offset)
+            .collect();
+        let columns: Vec<&BTreeMap<u64, u8>> = self
+            .samples
+            .iter()
+            .filter(|sample| rows.iter().any(|r| sample.contains_key(r)))
+            .collect();
+
+        let mut out = String::new();
+        for register_offset in rows {
+            let mut row = format!(
+                "0x{:02X}: {:02X}",
+                register_offset, self.initial[register_offset as usize]
+            );
+            for sample in &columns {
+                match sample.get(&register_offset) {
+                    Some(value) => write!(row, ",{:02X}", value).unwrap(),
+                    None => row.push_str("   "),
+                }
+            }
+            write!(row, "  {}", register_map.label(register_offset)).unwrap();
+            out.push_str(row.trim_end());
+            out.push('\n');
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn watches_ranges_minus_ignored_registers() {
+        let map = RegisterMap::parse_lines("ReadRegister 0xb2 RPM1 8").unwrap();
+        let filter = RegisterFilter::new(Some("0x40-0x42,RPM1"), Some("0x41"), &map).unwrap();
+        let watched: Vec<u64> = (0..EC_REGISTER_COUNT)
+            .filter(|&r| filter.includes(r))
+            .collect();
+        assert_eq!(watched, [0x40, 0x42, 0xb2]);
+        assert!(RegisterFilter::new(Some("0x42-0x40"), None, &map).is_err());
+        assert!(RegisterFilter::new(Some("0xb2-0x100"), None, &map).is_err());
+        assert!(RegisterFilter::new(None, Some("fan"), &map).is_err());
+    }
+
+    #[test]
+    fn renders_a_column_per_poll_that_changed_something() {
+        let mut registers = [0u8; EC_REGISTER_COUNT as usize];
+        let mut timeline = Timeline::new(RegisterFilter::default(), registers);
+        registers[0x40] = 0x01;
+        registers[0x41] = 0x05;
+        assert_eq!(timeline.record(Duration::from_secs(1), &registers).len(), 2);
+        assert!(timeline
+            .record(Duration::from_secs(2), &registers)
+            .is_empty());
+        registers[0x40] = 0x02;
+        let changes = timeline.record(Duration::from_secs(3), &registers);
+        assert_eq!(
+            changes,
+            [RegisterChange {
+                elapsed: Duration::from_secs(3),
+                register_offset: 0x40,
+                old: 0x01,
+                new: 0x02,
+            }]
+        );
+        let map = RegisterMap::parse_lines("Register 0x40 SW2S 1, 2, ACCC 1").unwrap();
+        assert_eq!(
+            timeline.render(None, &map),
+            "0x40: 00,01,02  ACCC, SW2S\n0x41: 00,05\n"
+        );
+        assert_eq!(timeline.render(Some(1), &map), "0x41: 00,05\n");
+    }
+
+    fn change() -> RegisterChange {
+        RegisterChange {
+            elapsed: Duration::from_millis(1500),
+            register_offset: 0xf4,
+            old: 0x09,
+            new: 0x47,
+        }
+    }
+
+    #[test]
+    fn quotes_labels_with_several_names_in_csv() {
+        assert_eq!(
+            change().to_csv("CPU_CONTROL, CPU_SPEED_CONTROL"),
+            "1500,0xf4,0x09,0x47,\"CPU_CONTROL, CPU_SPEED_CONTROL\""
+        );
+        assert_eq!(change().to_csv("RPM1"), "1500,0xf4,0x09,0x47,RPM1");
+        assert_eq!(change().to_csv("A\"B"), "1500,0xf4,0x09,0x47,\"A\"\"B\"");
+    }
+
+    #[test]
+    fn escapes_names_in_json() {
+        assert_eq!(
+            change().to_json("CPU_CONTROL, CPU_SPEED_CONTROL"),
+            "{\"elapsed_ms\":1500,\"register\":244,\"old\":9,\"new\":71,\
+             \"name\":\"CPU_CONTROL, CPU_SPEED_CONTROL\"}"
+        );
+        assert!(change()
+            .to_json("A\"B\\C\n")
+            .ends_with(",\"name\":\"A\\\"B\\\\C\\u000a\"}"));
+        assert!(!change().to_json("").contains("name"));
+    }
+}